@@ -0,0 +1,57 @@
+//! Embeds the simulator as a library: assembles a program from a string, runs it
+//! on a processor made with the builder and reads the results back through the
+//! public accessors. Run with `cargo run --example embed`.
+
+use cpusim::assembler;
+use cpusim::{HaltReason, Processor, Verbosity};
+
+/// Leaves the tenth Fibonacci number in r1 and in `result`. r0 and r1 hold the
+/// last two numbers; with no register move, copies are made by adding zero.
+const FIBONACCI: &str = "
+ldi 0 r0
+ldi 1 r1
+loop:
+add r0 r1 r2
+ldi 0 r3
+add r1 r3 r0
+add r2 r3 r1
+lod r3 count
+ldi 1 r2
+sub r3 r2 r3
+sto count r3
+cmp 0 r3
+jgt loop
+sto result r1
+hlt
+count:
+.word 9
+result:
+.word 0
+";
+
+fn main() {
+    let program = assembler::assemble_program(FIBONACCI).expect("the example program assembles");
+
+    let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).build();
+
+    cpu.load_program(&program.machine_code);
+
+    let reason = cpu.run();
+    let result = program.symbols["result"];
+
+    println!("stopped: {:?}", reason);
+    println!("registers: {:?}", cpu.registers());
+    println!("RAM[{}] (result): {}", result, cpu.ram()[result]);
+
+    assert_eq!(reason, HaltReason::Halted);
+    assert_eq!(cpu.registers()[1], 55);
+    assert_eq!(cpu.ram()[result], 55);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn computes_the_tenth_fibonacci_number() {
+        super::main();
+    }
+}
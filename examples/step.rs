@@ -0,0 +1,47 @@
+//! Drives the processor one instruction at a time, the way a frontend would,
+//! showing the machine state between steps. `step` never prints, so the only
+//! output besides this example's own is the countdown the program writes to the
+//! output port. Run with `cargo run --example step`.
+
+use cpusim::{assembler, describe_flag, disassemble, examples, HaltReason, Processor, StepResult};
+
+fn main() {
+    let source = examples::find("countdown").expect("the countdown example exists");
+    let program = assembler::assemble(source).expect("the countdown example assembles");
+
+    let mut cpu = Processor::new();
+
+    cpu.load_program(&program);
+
+    let reason = loop {
+        let program_counter = cpu.program_counter();
+        let instruction = cpu.ram()[program_counter];
+
+        let result = cpu.step();
+
+        println!(
+            "{:>2}: {:<24} registers: {:?}  flags: {}",
+            program_counter,
+            disassemble(instruction),
+            cpu.registers(),
+            describe_flag(cpu.flags())
+        );
+
+        if let StepResult::Stopped(reason) = result {
+            break reason;
+        }
+    };
+
+    println!("stopped: {:?}", reason);
+
+    assert_eq!(reason, HaltReason::Halted);
+    assert_eq!(cpu.registers()[0], 0);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn counts_down_to_zero() {
+        super::main();
+    }
+}
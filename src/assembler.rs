@@ -0,0 +1,1330 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::binary::{bin_raw_as_machine_code, machine_code_as_bin_raw};
+use crate::{disassemble, get_opcode_name, jump_offset, OPERAND_MASK};
+
+/// Width of the target address field of jump instructions.
+const JUMP_ADDRESS_BITS: u32 = 5;
+/// Width of the RAM address field of `sto`, `lod` and `lea`.
+const RAM_ADDRESS_BITS: u32 = 6;
+/// Width of the signed offset field of `jr`, giving a reach of -16 to +15 words.
+const JUMP_OFFSET_BITS: u32 = 5;
+/// Width of `bit`'s bit index field, enough to pick any bit of a word.
+const BIT_INDEX_BITS: u32 = 5;
+
+const JR_OPCODE: u32 = 0b010110;
+
+#[derive(Debug, Clone, Default)]
+pub struct AssembleOptions {
+    /// Position-independent code: `jmp label` is linked as a relative `jr` whenever
+    /// the label is within `jr`'s reach, and stays absolute otherwise.
+    pub pic: bool,
+    /// Values for `.if` conditions, as given with `-D NAME=value`. These take
+    /// precedence over `.equ` constants of the same name.
+    pub defines: HashMap<String, u32>,
+    /// Restricts which instructions may be used; `None` allows all of them.
+    pub profile: Option<IsaProfile>
+}
+
+/// A named subset of the instruction set, for introducing instructions a few at a
+/// time. Assembling an instruction outside the profile fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsaProfile {
+    name: String,
+    /// Bit `n` is set when opcode `n` is allowed.
+    allowed: u64
+}
+
+impl IsaProfile {
+    /// A profile allowing exactly the given mnemonics.
+    pub fn new(name: &str, mnemonics: &[&str]) -> IsaProfile {
+        let mut allowed = 0;
+
+        for &mnemonic in mnemonics {
+            let opcode = (0..64)
+                .find(|&opcode| get_opcode_name(opcode) == mnemonic)
+                .unwrap_or_else(|| panic!("Unknown mnemonic '{}' in profile '{}'.", mnemonic, name));
+
+            allowed |= 1 << opcode;
+        }
+
+        IsaProfile { name: name.to_string(), allowed }
+    }
+
+    /// The original 4-bit instruction set, before the extended opcodes.
+    pub fn basic() -> IsaProfile {
+        IsaProfile::new("basic", &["nop", "ldi", "add", "sub", "cmp", "jmp", "jeq", "jgt", "jlt", "sto", "lod", "hlt"])
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn allows(&self, opcode: u32) -> bool {
+        opcode < 64 && self.allowed & (1 << opcode) != 0
+    }
+}
+
+/// What a relocated address field points at.
+enum Target {
+    /// A named label or `.equ` constant, looked up when linking.
+    Symbol(String),
+    /// A local numeric label, already resolved to an offset within the module.
+    Local(usize)
+}
+
+/// A label used as an address operand, patched into the instruction once the label's
+/// address is known.
+struct Relocation {
+    offset: usize,
+    target: Target,
+    /// Position of the address field within the instruction.
+    shift: u32,
+    /// Width of the address field in bits.
+    width: u32,
+    /// Link as a relative `jr` if the target is in reach (see `AssembleOptions::pic`).
+    relative: bool,
+    /// Added to the target's address, from a `label+offset` operand.
+    addend: u32
+}
+
+/// One assembled source file: machine code, the labels it defines and the label
+/// references it still needs resolved.
+pub struct Module {
+    code: Vec<u32>,
+    symbols: HashMap<String, usize>,
+    constants: HashMap<String, u32>,
+    relocations: Vec<Relocation>,
+    /// Latest definition of each local numeric label since the last named label.
+    local_labels: HashMap<u32, usize>,
+    /// Forward references (`1f`) still waiting for their label: relocation index and label number.
+    pending_forward: Vec<(usize, u32)>,
+    /// Source line (from 1) of each word in `code`.
+    lines: Vec<usize>,
+    /// Whether each word in `code` is an instruction or `.word` data.
+    kinds: Vec<RegionKind>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// A label was referenced but no module defines it.
+    UndefinedSymbol(String),
+    /// More than one module defines the same label.
+    DuplicateSymbol(String),
+    /// A label's address doesn't fit in the address field of an instruction using it.
+    AddressOutOfRange { address: u32, width: u32 }
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbol(name) => write!(f, "undefined label '{}'", name),
+            LinkError::DuplicateSymbol(name) => write!(f, "label '{}' is defined more than once", name),
+            LinkError::AddressOutOfRange { address, width } => {
+                write!(f, "label address {} doesn't fit in a {}-bit address field", address, width)
+            }
+        }
+    }
+}
+
+/// Why a line failed to assemble.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnknownMnemonic(String),
+    /// A `:` with no label name before it.
+    InvalidLabel(String),
+    WrongOperandCount { expected: usize, found: usize },
+    InvalidImmediate(String),
+    InvalidRegister(String),
+    /// A numeric operand doesn't fit in its field.
+    OutOfRange { operand: String, width: u32 },
+    /// A `1b` with no `1:` before it, or a `1f` with none after it in the same scope.
+    UndefinedLocalLabel(String),
+    /// `.else`, `.endif` or `.if` without its partner.
+    UnbalancedConditional(&'static str),
+    NotInProfile { mnemonic: String, profile: String },
+    /// A jump lands on a `.word` rather than an instruction.
+    JumpIntoData(usize),
+    Link(LinkError)
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic '{}'", mnemonic),
+            ErrorKind::InvalidLabel(term) => write!(f, "'{}' is not a valid label", term),
+            ErrorKind::WrongOperandCount { expected, found } => {
+                write!(f, "expected {} operands, found {}", expected, found)
+            }
+            ErrorKind::InvalidImmediate(operand) => write!(f, "operand '{}' is not a valid immediate", operand),
+            ErrorKind::InvalidRegister(operand) => write!(f, "operand '{}' is not a valid register", operand),
+            ErrorKind::OutOfRange { operand, width } => {
+                write!(f, "operand '{}' doesn't fit in a {}-bit field", operand, width)
+            }
+            ErrorKind::UndefinedLocalLabel(label) => write!(f, "local label '{}' has no matching definition", label),
+            ErrorKind::UnbalancedConditional(directive) => write!(f, "'{}' has no matching directive", directive),
+            ErrorKind::NotInProfile { mnemonic, profile } => {
+                write!(f, "'{}' is not part of the '{}' instruction profile", mnemonic, profile)
+            }
+            ErrorKind::JumpIntoData(address) => write!(f, "jump target {} is data, not an instruction", address),
+            ErrorKind::Link(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+/// An error in a source file, with the line (from 1) it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    /// The offending line, trimmed.
+    pub text: String,
+    pub kind: ErrorKind
+}
+
+impl AssembleError {
+    fn new(line: usize, text: &str, kind: ErrorKind) -> AssembleError {
+        AssembleError { line, text: text.trim().to_string(), kind }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+/// Number of registers an instruction's 2-bit register field can name.
+const REGISTER_COUNT: u32 = 4;
+/// Width of the immediate field of `ldi` and `cmp`.
+const IMMEDIATE_BITS: u32 = 16;
+
+fn parse_register(term: &str) -> Result<u32, ErrorKind> {
+    let register = term
+        .trim_start_matches('r')
+        .parse::<u32>()
+        .map_err(|_| ErrorKind::InvalidRegister(term.to_string()))?;
+
+    if register >= REGISTER_COUNT {
+        return Err(ErrorKind::OutOfRange { operand: term.to_string(), width: 2 });
+    }
+
+    Ok(register)
+}
+
+fn parse_immediate(term: &str) -> Result<u32, ErrorKind> {
+    term.parse::<u32>().map_err(|_| ErrorKind::InvalidImmediate(term.to_string()))
+}
+
+/// Parses an immediate that has to fit in a `width`-bit field.
+fn parse_field(term: &str, width: u32) -> Result<u32, ErrorKind> {
+    let value = parse_immediate(term)?;
+
+    if value >> width != 0 {
+        return Err(ErrorKind::OutOfRange { operand: term.to_string(), width });
+    }
+
+    Ok(value)
+}
+
+fn parse_jump_offset(term: &str) -> Result<u32, ErrorKind> {
+    let offset = term.parse::<i32>().map_err(|_| ErrorKind::InvalidImmediate(term.to_string()))?;
+
+    if !fits_signed(offset as i64, JUMP_OFFSET_BITS) {
+        return Err(ErrorKind::OutOfRange { operand: term.to_string(), width: JUMP_OFFSET_BITS });
+    }
+
+    Ok(offset as u32 & ((1 << JUMP_OFFSET_BITS) - 1))
+}
+
+fn fits_signed(value: i64, width: u32) -> bool {
+    let limit = 1 << (width - 1);
+
+    (-limit..limit).contains(&value)
+}
+
+fn parse_bit_index(term: &str) -> Result<u32, ErrorKind> {
+    parse_field(term, BIT_INDEX_BITS)
+}
+
+/// Parses a local label reference such as `1f` (next `1:`) or `1b` (previous `1:`),
+/// returning the label number and whether it looks forward.
+fn parse_local_reference(term: &str) -> Option<(u32, bool)> {
+    let (number, direction) = term.split_at(term.len().checked_sub(1)?);
+    let number = number.parse::<u32>().ok()?;
+
+    match direction {
+        "f" => Some((number, true)),
+        "b" => Some((number, false)),
+        _ => None
+    }
+}
+
+impl Module {
+    fn new() -> Module {
+        Module {
+            code: Vec::new(),
+            symbols: HashMap::new(),
+            constants: HashMap::new(),
+            relocations: Vec::new(),
+            local_labels: HashMap::new(),
+            pending_forward: Vec::new(),
+            lines: Vec::new(),
+            kinds: Vec::new()
+        }
+    }
+
+    /// Encodes a numeric address operand into the `width`-bit field at `shift`, or
+    /// records a relocation if the operand names a label or `.equ` constant.
+    fn address(&mut self, term: &str, shift: u32, width: u32) -> Result<u32, ErrorKind> {
+        if term.parse::<u32>().is_ok() {
+            return Ok(parse_field(term, width)? << shift);
+        }
+
+        let target = match parse_local_reference(term) {
+            Some((number, true)) => {
+                self.pending_forward.push((self.relocations.len(), number));
+
+                Target::Local(0)
+            }
+            Some((number, false)) => match self.local_labels.get(&number) {
+                Some(&offset) => Target::Local(offset),
+                None => return Err(ErrorKind::UndefinedLocalLabel(term.to_string()))
+            },
+            None => Target::Symbol(term.to_string())
+        };
+
+        self.relocations.push(Relocation {
+            offset: self.code.len(),
+            target,
+            shift,
+            width,
+            relative: false,
+            addend: 0
+        });
+
+        Ok(0)
+    }
+
+    /// Like `address`, but also accepts `base+offset`, where `base` is a number, label
+    /// or constant and `offset` a number.
+    fn effective_address(&mut self, term: &str, shift: u32, width: u32) -> Result<u32, ErrorKind> {
+        let Some((base, offset)) = term.split_once('+') else {
+            return self.address(term, shift, width);
+        };
+
+        let offset = parse_immediate(offset)?;
+
+        if let Ok(base) = base.parse::<u32>() {
+            let address = base.saturating_add(offset);
+
+            if address >> width != 0 {
+                return Err(ErrorKind::OutOfRange { operand: term.to_string(), width });
+            }
+
+            return Ok(address << shift);
+        }
+
+        let field = self.address(base, shift, width)?;
+
+        if let Some(relocation) = self.relocations.last_mut() {
+            relocation.addend = offset;
+        }
+
+        Ok(field)
+    }
+
+    /// Names the next word of code, failing if the name is already a label or constant.
+    fn define_symbol(&mut self, name: &str) -> Result<(), ErrorKind> {
+        self.check_unused(name)?;
+        self.symbols.insert(name.to_string(), self.code.len());
+
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &str, value: u32) -> Result<(), ErrorKind> {
+        self.check_unused(name)?;
+        self.constants.insert(name.to_string(), value);
+
+        Ok(())
+    }
+
+    fn check_unused(&self, name: &str) -> Result<(), ErrorKind> {
+        if self.symbols.contains_key(name) || self.constants.contains_key(name) {
+            return Err(ErrorKind::Link(LinkError::DuplicateSymbol(name.to_string())));
+        }
+
+        Ok(())
+    }
+
+    /// Lets the relocation recorded for the current instruction, if any, be linked as a
+    /// relative jump.
+    fn allow_relative(&mut self) {
+        if let Some(relocation) = self.relocations.last_mut() {
+            if relocation.offset == self.code.len() {
+                relocation.relative = true;
+            }
+        }
+    }
+
+    /// Defines local label `number` at the current address, resolving any forward
+    /// references waiting for it.
+    fn define_local(&mut self, number: u32) {
+        let offset = self.code.len();
+        let relocations = &mut self.relocations;
+
+        self.pending_forward.retain(|&(index, pending)| {
+            if pending == number {
+                relocations[index].target = Target::Local(offset);
+            }
+
+            pending != number
+        });
+
+        self.local_labels.insert(number, offset);
+    }
+
+    /// Closes the current local label scope, at a named label or the end of the module.
+    /// Fails with the source line of a forward reference whose label never came.
+    fn end_local_scope(&mut self) -> Result<(), (usize, ErrorKind)> {
+        if let Some(&(index, number)) = self.pending_forward.first() {
+            let line = self.lines[self.relocations[index].offset];
+
+            return Err((line, ErrorKind::UndefinedLocalLabel(format!("{}f", number))));
+        }
+
+        self.local_labels.clear();
+
+        Ok(())
+    }
+}
+
+/// The part of a line before any `;` or `#` comment.
+fn strip_comment(line: &str) -> &str {
+    line.find([';', '#']).map_or(line, |start| &line[..start])
+}
+
+/// One level of `.if` nesting.
+struct Conditional {
+    /// Whether the code around the `.if` is being assembled.
+    parent_active: bool,
+    condition: bool,
+    in_else: bool
+}
+
+/// Applies conditional assembly. `.if NAME` is taken when `NAME` is defined (by
+/// `-D` or an earlier `.equ`) with a nonzero value; `.else` and `.endif` work as
+/// usual and may nest. Lines in branches not taken are blanked rather than removed,
+/// so every kept line stays on its original line number. Comments are stripped
+/// here too, leaving a comment-only line blank. Both `\n` and `\r\n` line endings
+/// are accepted.
+fn preprocess<'a>(source: &'a str, options: &AssembleOptions) -> Result<Vec<&'a str>, AssembleError> {
+    let mut values: HashMap<&str, u32> = HashMap::new();
+    let mut conditionals: Vec<Conditional> = Vec::new();
+    let mut lines = Vec::new();
+
+    let mut last_if = 0;
+
+    for (i, line) in source.lines().enumerate() {
+        let line = strip_comment(line);
+        let terms: Vec<&str> = line.split_whitespace().collect();
+        let error = |kind| AssembleError::new(i + 1, line, kind);
+        let active = conditionals
+            .last()
+            .is_none_or(|conditional| conditional.parent_active && conditional.condition != conditional.in_else);
+
+        match terms.first().copied() {
+            Some(".if") => {
+                expect_operands(&terms, 1).map_err(error)?;
+
+                let value = options.defines.get(terms[1]).or(values.get(terms[1]));
+
+                last_if = i;
+
+                conditionals.push(Conditional {
+                    parent_active: active,
+                    condition: value.is_some_and(|&value| value != 0),
+                    in_else: false
+                });
+
+                lines.push("");
+            }
+            Some(".else") => {
+                match conditionals.last_mut() {
+                    Some(conditional) if !conditional.in_else => conditional.in_else = true,
+                    _ => return Err(error(ErrorKind::UnbalancedConditional(".else")))
+                }
+
+                lines.push("");
+            }
+            Some(".endif") => {
+                if conditionals.pop().is_none() {
+                    return Err(error(ErrorKind::UnbalancedConditional(".endif")));
+                }
+
+                lines.push("");
+            }
+            _ if active => {
+                if terms.first() == Some(&".equ") {
+                    expect_operands(&terms, 2).map_err(error)?;
+                    values.insert(terms[1], parse_immediate(terms[2]).map_err(error)?);
+                }
+
+                lines.push(line);
+            }
+            _ => lines.push("")
+        }
+    }
+
+    if !conditionals.is_empty() {
+        let line = source.lines().nth(last_if).unwrap_or("");
+
+        return Err(AssembleError::new(last_if + 1, line, ErrorKind::UnbalancedConditional(".if")));
+    }
+
+    Ok(lines)
+}
+
+/// Checks that `terms` is a mnemonic or directive followed by `expected` operands.
+fn expect_operands(terms: &[&str], expected: usize) -> Result<(), ErrorKind> {
+    let found = terms.len() - 1;
+
+    if found != expected {
+        return Err(ErrorKind::WrongOperandCount { expected, found });
+    }
+
+    Ok(())
+}
+
+/// The number of operands each mnemonic and directive takes, or `None` if it is
+/// unknown.
+fn operand_count(mnemonic: &str) -> Option<usize> {
+    let count = match mnemonic {
+        "nop" | "hlt" | "dbg" => 0,
+        ".word" | "jmp" | "jr" | "jeq" | "jgt" | "jlt" | "jge" | "jle" | "jne" | "jcs" | "neg" | "not" | "rdpc" => 1,
+        ".equ" | "ldi" | "cmp" | "sto" | "lod" | "lea" | "ldx" | "stx" | "cmoveq" | "cmovgt" | "cmovlt" | "bit" => 2,
+        "add" | "sub" | "mul" | "and" | "or" | "xor" | "shl" | "shr" => 3,
+        _ => return None
+    };
+
+    Some(count)
+}
+
+/// Encodes one instruction or `.word` line, recording relocations for label operands
+/// in `module`.
+fn assemble_line(module: &mut Module, terms: &[&str], options: &AssembleOptions) -> Result<u32, ErrorKind> {
+    let expected = operand_count(terms[0]).ok_or_else(|| ErrorKind::UnknownMnemonic(terms[0].to_string()))?;
+
+    expect_operands(terms, expected)?;
+
+    let instruction = match terms[0] {
+        ".word" => parse_immediate(terms[1])?,
+        "nop" => 0,
+        "ldi" => {
+            (0b0001 << 18) | (parse_field(terms[1], IMMEDIATE_BITS)? << 2) | parse_register(terms[2])?
+        },
+        "add" => {
+            (0b0010 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "sub" => {
+            (0b0011 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "mul" => {
+            (0b100001 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "and" => {
+            (0b1011 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "or" => {
+            (0b1100 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "xor" => {
+            (0b1101 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "shl" => {
+            (0b1110 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "shr" => {
+            (0b011101 << 18) | (parse_register(terms[1])? << 4) | (parse_register(terms[2])? << 2) | parse_register(terms[3])?
+        },
+        "cmp" => {
+            (0b0100 << 18) | (parse_field(terms[1], IMMEDIATE_BITS)? << 2) | parse_register(terms[2])?
+        },
+        "jmp" => {
+            let instruction = (0b0101 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?;
+
+            if options.pic {
+                module.allow_relative();
+            }
+
+            instruction
+        },
+        "jr" => (JR_OPCODE << 18) | parse_jump_offset(terms[1])?,
+        "jeq" => (0b0110 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?,
+        "jgt" => (0b0111 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?,
+        "jlt" => (0b1000 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?,
+        "jge" => (0b010010 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?,
+        "jle" => (0b010011 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?,
+        "jne" => (0b010100 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?,
+        "jcs" => (0b011100 << 18) | module.address(terms[1], 0, JUMP_ADDRESS_BITS)?,
+        "sto" => {
+            (0b1001 << 18) | module.address(terms[1], 2, RAM_ADDRESS_BITS)? | parse_register(terms[2])?
+        },
+        "lod" => {
+            (0b1010 << 18) | module.address(terms[2], 2, RAM_ADDRESS_BITS)? | parse_register(terms[1])?
+        },
+        "lea" => {
+            (0b010111 << 18) | module.effective_address(terms[2], 2, RAM_ADDRESS_BITS)? | parse_register(terms[1])?
+        },
+        "cmoveq" => (0b011000 << 18) | (parse_register(terms[2])? << 2) | parse_register(terms[1])?,
+        "cmovgt" => (0b011001 << 18) | (parse_register(terms[2])? << 2) | parse_register(terms[1])?,
+        "cmovlt" => (0b011010 << 18) | (parse_register(terms[2])? << 2) | parse_register(terms[1])?,
+        "ldx" => (0b011111 << 18) | (parse_register(terms[2])? << 2) | parse_register(terms[1])?,
+        "stx" => (0b100000 << 18) | (parse_register(terms[1])? << 2) | parse_register(terms[2])?,
+        "hlt" => 0b1111 << 18,
+        "dbg" => 0b011011 << 18,
+        "neg" => (0b010000 << 18) | parse_register(terms[1])?,
+        "not" => (0b011110 << 18) | parse_register(terms[1])?,
+        "rdpc" => (0b010101 << 18) | parse_register(terms[1])?,
+        "bit" => {
+            (0b010001 << 18) | (parse_bit_index(terms[2])? << 2) | parse_register(terms[1])?
+        },
+        _ => return Err(ErrorKind::UnknownMnemonic(terms[0].to_string()))
+    };
+
+    Ok(instruction)
+}
+
+/// Assembles a source file into a module. Labels (`name:`, on their own line or
+/// in front of an instruction) are recorded relative to the start of the module
+/// and resolved later by `link`, so any address operand may name one. Defining a
+/// name twice is an error. A `;` or `#` starts a comment running to the end of the
+/// line; blank and comment-only lines assemble to nothing.
+///
+/// Numeric labels (`1:`) are local to the nearest preceding named label and are
+/// referenced as `1f` (the next `1:`) or `1b` (the previous one), so repeated code
+/// does not need unique label names.
+///
+/// Directives:
+/// - `.equ NAME value` defines a constant usable wherever an address is expected.
+/// - `.word value` emits a raw data word, typically after a label naming it.
+pub fn assemble_module(source: &str) -> Result<Module, AssembleError> {
+    assemble_module_with(source, &AssembleOptions::default())
+}
+
+pub fn assemble_module_with(source: &str, options: &AssembleOptions) -> Result<Module, AssembleError> {
+    let mut module = Module::new();
+    let located = |(line, kind): (usize, ErrorKind)| {
+        AssembleError::new(line, source.lines().nth(line - 1).unwrap_or(""), kind)
+    };
+
+    for (i, line) in preprocess(source, options)?.into_iter().enumerate() {
+        let terms: Vec<&str> = line.split_whitespace().collect();
+        let error = |kind| AssembleError::new(i + 1, line, kind);
+        let mut terms = &terms[..];
+
+        // Leading labels name the instruction after them, on this line or a later one.
+        while let Some(name) = terms.first().and_then(|term| term.strip_suffix(':')) {
+            if name.is_empty() {
+                return Err(error(ErrorKind::InvalidLabel(terms[0].to_string())));
+            }
+
+            match name.parse::<u32>() {
+                Ok(number) => module.define_local(number),
+                Err(_) => {
+                    module.end_local_scope().map_err(located)?;
+                    module.define_symbol(name).map_err(error)?;
+                }
+            }
+
+            terms = &terms[1..];
+        }
+
+        if terms.is_empty() {
+            continue;
+        }
+
+        if terms[0] == ".equ" {
+            expect_operands(terms, 2).map_err(error)?;
+
+            let value = parse_immediate(terms[2]).map_err(error)?;
+
+            module.define_constant(terms[1], value).map_err(error)?;
+            continue;
+        }
+
+        let instruction = assemble_line(&mut module, terms, options).map_err(error)?;
+
+        if let Some(profile) = &options.profile {
+            if terms[0] != ".word" && !profile.allows(instruction >> 18) {
+                return Err(error(ErrorKind::NotInProfile {
+                    mnemonic: terms[0].to_string(),
+                    profile: profile.name().to_string()
+                }));
+            }
+        }
+
+        module.code.push(instruction);
+        module.lines.push(i + 1);
+        module.kinds.push(if terms[0] == ".word" { RegionKind::Data } else { RegionKind::Code });
+    }
+
+    module.end_local_scope().map_err(located)?;
+
+    Ok(module)
+}
+
+/// A single decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub opcode: u32,
+    pub operand: u32
+}
+
+impl Instruction {
+    pub fn from_word(word: u32) -> Instruction {
+        Instruction {
+            opcode: word >> 18,
+            operand: word & OPERAND_MASK
+        }
+    }
+
+    /// The machine code word for this instruction.
+    pub fn encoding(self) -> u32 {
+        (self.opcode << 18) | self.operand
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseInstructionError {
+    Empty,
+    UnknownMnemonic(String),
+    /// Labels, directives and label operands need a whole program to make sense.
+    NeedsProgram(String),
+    /// Any other problem with the operands.
+    Invalid(ErrorKind)
+}
+
+impl fmt::Display for ParseInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseInstructionError::Empty => write!(f, "no instruction given"),
+            ParseInstructionError::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic '{}'", mnemonic),
+            ParseInstructionError::NeedsProgram(line) => write!(f, "'{}' can only be assembled as part of a program", line),
+            ParseInstructionError::Invalid(kind) => write!(f, "{}", kind)
+        }
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = ParseInstructionError;
+
+    /// Parses one line of assembly, such as `"add r1 r2 r3"`, with numeric operands only.
+    fn from_str(line: &str) -> Result<Instruction, ParseInstructionError> {
+        let terms: Vec<&str> = line.split_whitespace().collect();
+
+        let Some(&mnemonic) = terms.first() else {
+            return Err(ParseInstructionError::Empty);
+        };
+
+        if mnemonic.starts_with('.') || mnemonic.ends_with(':') {
+            return Err(ParseInstructionError::NeedsProgram(line.trim().to_string()));
+        }
+
+        let mut module = Module::new();
+        let word = assemble_line(&mut module, &terms, &AssembleOptions::default()).map_err(|kind| match kind {
+            ErrorKind::UnknownMnemonic(mnemonic) => ParseInstructionError::UnknownMnemonic(mnemonic),
+            kind => ParseInstructionError::Invalid(kind)
+        })?;
+
+        if !module.relocations.is_empty() {
+            return Err(ParseInstructionError::NeedsProgram(line.trim().to_string()));
+        }
+
+        Ok(Instruction::from_word(word))
+    }
+}
+
+/// Lays the modules out one after another and resolves every label reference
+/// against the labels defined across all of them.
+pub fn link(modules: &[Module]) -> Result<Vec<u32>, LinkError> {
+    link_located(modules).map_err(|(err, _)| err)
+}
+
+/// A link error and, if a label reference caused it, the index of the module and
+/// the offset of the word with the reference.
+type LocatedLinkError = (LinkError, Option<(usize, usize)>);
+
+fn link_located(modules: &[Module]) -> Result<Vec<u32>, LocatedLinkError> {
+    let mut symbols: HashMap<&str, u32> = HashMap::new();
+    let mut base = 0;
+
+    for module in modules {
+        for (name, &offset) in &module.symbols {
+            if symbols.insert(name, (base + offset) as u32).is_some() {
+                return Err((LinkError::DuplicateSymbol(name.clone()), None));
+            }
+        }
+
+        for (name, &value) in &module.constants {
+            if symbols.insert(name, value).is_some() {
+                return Err((LinkError::DuplicateSymbol(name.clone()), None));
+            }
+        }
+
+        base += module.code.len();
+    }
+
+    let mut program = Vec::new();
+
+    for (index, module) in modules.iter().enumerate() {
+        let base = program.len();
+
+        program.extend_from_slice(&module.code);
+
+        for relocation in &module.relocations {
+            let location = Some((index, relocation.offset));
+            let address = match &relocation.target {
+                Target::Symbol(name) => *symbols
+                    .get(name.as_str())
+                    .ok_or_else(|| (LinkError::UndefinedSymbol(name.clone()), location))?,
+                Target::Local(offset) => (base + offset) as u32
+            } + relocation.addend;
+
+            if relocation.relative {
+                let distance = address as i64 - (base + relocation.offset) as i64;
+
+                if fits_signed(distance, JUMP_OFFSET_BITS) {
+                    let offset_mask = (1 << JUMP_OFFSET_BITS) - 1;
+
+                    program[base + relocation.offset] = (JR_OPCODE << 18) | (distance as u32 & offset_mask);
+                    continue;
+                }
+            }
+
+            if address >> relocation.width != 0 {
+                return Err((LinkError::AddressOutOfRange { address, width: relocation.width }, location));
+            }
+
+            program[base + relocation.offset] |= address << relocation.shift;
+        }
+    }
+
+    Ok(program)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Code,
+    /// Words emitted by `.word`.
+    Data
+}
+
+/// A run of consecutive words of the same kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub len: usize,
+    pub kind: RegionKind
+}
+
+/// Which parts of an assembled program are code and which are data, in address order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>
+}
+
+impl MemoryMap {
+    fn from_kinds(kinds: &[RegionKind]) -> MemoryMap {
+        let mut regions: Vec<Region> = Vec::new();
+
+        for (address, &kind) in kinds.iter().enumerate() {
+            match regions.last_mut() {
+                Some(region) if region.kind == kind => region.len += 1,
+                _ => regions.push(Region { start: address, len: 1, kind })
+            }
+        }
+
+        MemoryMap { regions }
+    }
+
+    /// The kind of the word at `address`, or `None` past the end of the program.
+    pub fn kind_at(&self, address: usize) -> Option<RegionKind> {
+        self.regions
+            .iter()
+            .find(|region| (region.start..region.start + region.len).contains(&address))
+            .map(|region| region.kind)
+    }
+}
+
+/// Everything the assembler knows about a program, for tools that need more than
+/// the machine code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledProgram {
+    pub machine_code: Vec<u32>,
+    /// The address of every named label.
+    pub symbols: HashMap<String, usize>,
+    pub memory_map: MemoryMap,
+    /// The source line (from 1) each word of `machine_code` came from.
+    pub source_map: Vec<usize>
+}
+
+impl AssembledProgram {
+    /// The machine code as raw little-endian bytes.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        machine_code_as_bin_raw(&self.machine_code)
+    }
+}
+
+/// Where the instruction at `address` can jump to, or `None` if it isn't a jump.
+fn jump_destination(address: usize, instruction: u32) -> Option<usize> {
+    let operand = instruction & OPERAND_MASK;
+
+    match instruction >> 18 {
+        5..=8 | 18..=20 | 28 => Some((operand & 0b11111) as usize),
+        22 => usize::try_from(address as i64 + jump_offset(operand) as i64).ok(),
+        _ => None
+    }
+}
+
+/// The verify pass: checks that every jump in the code lands on an instruction.
+/// Data words are skipped even if they happen to decode as a jump. No instruction
+/// is more than one word long, so a target can't fall mid-instruction. Fails with
+/// the address of the first jump that lands on data.
+fn verify(machine_code: &[u32], memory_map: &MemoryMap) -> Result<(), (usize, ErrorKind)> {
+    for (address, &word) in machine_code.iter().enumerate() {
+        if memory_map.kind_at(address) != Some(RegionKind::Code) {
+            continue;
+        }
+
+        let Some(target) = jump_destination(address, word) else {
+            continue;
+        };
+
+        if memory_map.kind_at(target) == Some(RegionKind::Data) {
+            return Err((address, ErrorKind::JumpIntoData(target)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Assembles a single self-contained source file.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AssembleError> {
+    assemble_with(source, &AssembleOptions::default())
+}
+
+pub fn assemble_with(source: &str, options: &AssembleOptions) -> Result<Vec<u32>, AssembleError> {
+    Ok(assemble_program_with(source, options)?.machine_code)
+}
+
+/// Assembles a single self-contained source file, keeping its symbols and maps.
+pub fn assemble_program(source: &str) -> Result<AssembledProgram, AssembleError> {
+    assemble_program_with(source, &AssembleOptions::default())
+}
+
+pub fn assemble_program_with(source: &str, options: &AssembleOptions) -> Result<AssembledProgram, AssembleError> {
+    let module = assemble_module_with(source, options)?;
+
+    let machine_code = link_located(std::slice::from_ref(&module)).map_err(|(err, location)| {
+        // Within one module a name can't be defined twice, so only label references fail.
+        let (_, offset) = location.expect("a lone module's symbols are checked as they are defined");
+        let line = module.lines[offset];
+
+        AssembleError::new(line, source.lines().nth(line - 1).unwrap_or(""), ErrorKind::Link(err))
+    })?;
+
+    let memory_map = MemoryMap::from_kinds(&module.kinds);
+
+    verify(&machine_code, &memory_map).map_err(|(address, kind)| {
+        let line = module.lines[address];
+
+        AssembleError::new(line, source.lines().nth(line - 1).unwrap_or(""), kind)
+    })?;
+
+    Ok(AssembledProgram {
+        machine_code,
+        memory_map,
+        symbols: module.symbols,
+        source_map: module.lines
+    })
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    NotFound(PathBuf),
+    /// The file exists but isn't text, e.g. an assembled binary given by mistake.
+    NotUtf8(PathBuf),
+    /// Any other failure to read the file.
+    Io(PathBuf, io::Error),
+    Assemble(PathBuf, AssembleError)
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileError::NotFound(path) => write!(f, "{}: file not found", path.display()),
+            FileError::NotUtf8(path) => {
+                write!(f, "{}: not a UTF-8 text file (is it an assembled binary?)", path.display())
+            }
+            FileError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            FileError::Assemble(path, err) => write!(f, "{}: {}", path.display(), err)
+        }
+    }
+}
+
+/// Reads and assembles a source file.
+pub fn assemble_from_file(path: impl AsRef<Path>, options: &AssembleOptions) -> Result<Vec<u32>, FileError> {
+    let path = path.as_ref();
+
+    let source = std::fs::read_to_string(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => FileError::NotFound(path.to_path_buf()),
+        io::ErrorKind::InvalidData => FileError::NotUtf8(path.to_path_buf()),
+        _ => FileError::Io(path.to_path_buf(), err)
+    })?;
+
+    assemble_with(&source, options).map_err(|err| FileError::Assemble(path.to_path_buf(), err))
+}
+
+/// Assembles `source` and checks it against the raw binary at `reference`, for
+/// golden-file tests of the encoding. Panics at the first differing word, showing
+/// both words and their disassembly.
+pub fn assert_matches_reference(source: &str, reference: impl AsRef<Path>) {
+    let reference = reference.as_ref();
+    let bytes = std::fs::read(reference).unwrap_or_else(|err| panic!("{}: {}", reference.display(), err));
+
+    let expected = bin_raw_as_machine_code(&bytes).unwrap_or_else(|err| panic!("{}: {}", reference.display(), err));
+    let actual = assemble(source).unwrap_or_else(|err| panic!("{}", err));
+
+    let describe = |word: Option<&u32>| match word {
+        Some(&word) => format!("{:08x} ({})", word, disassemble(word)),
+        None => "nothing".to_string()
+    };
+
+    if let Some(i) = (0..actual.len().max(expected.len())).find(|&i| actual.get(i) != expected.get(i)) {
+        panic!(
+            "Assembled program differs from {} at word {}: assembled {}, reference has {}.",
+            reference.display(),
+            i,
+            describe(actual.get(i)),
+            describe(expected.get(i))
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HaltReason, Processor, Verbosity, OPERAND_MASK};
+
+    /// A path in the system temp directory unique to this test process.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cpusim-{}-{}", std::process::id(), name))
+    }
+
+    /// Runs `program` quietly to completion.
+    fn run(program: &[u32]) -> (Processor, HaltReason) {
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).build();
+
+        cpu.load_program(program);
+
+        let reason = cpu.run();
+
+        (cpu, reason)
+    }
+
+    #[test]
+    fn a_jump_across_modules_links_to_the_other_module() {
+        let a = assemble_module("ldi 21 r0\njmp double\n").unwrap();
+        let b = assemble_module("double:\nadd r0 r0 r0\nhlt\n").unwrap();
+
+        let program = link(&[a, b]).unwrap();
+
+        // B's code follows A's two words, so `double` is address 2.
+        assert_eq!(program.len(), 4);
+        assert_eq!(program[1], (0b0101 << 18) | 2);
+
+        let a = assemble_module("jmp double\n").unwrap();
+
+        assert_eq!(link(&[a]), Err(LinkError::UndefinedSymbol("double".to_string())));
+    }
+
+    #[test]
+    fn labelled_addresses_encode_like_numeric_ones() {
+        let named = assemble(".equ slot 40\nldi 9 r1\nsto slot r1\nlod r2 slot\nhlt\n").unwrap();
+        let numeric = assemble("ldi 9 r1\nsto 40 r1\nlod r2 40\nhlt\n").unwrap();
+
+        assert_eq!(named, numeric);
+
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+        cpu.load_program(&assemble("ldi 9 r1\nsto result r1\nlod r2 result\nhlt\nresult:\n.word 0\n").unwrap());
+        cpu.run();
+
+        assert_eq!(cpu.ram[4], 9);
+        assert_eq!(cpu.registers()[2], 9);
+    }
+
+    #[test]
+    fn numeric_labels_resolve_to_their_nearest_definition() {
+        let program = assemble("
+first:
+ldi 1 r1
+ldi 3 r0
+1:
+sub r0 r1 r0
+cmp 0 r0
+jgt 1b
+second:
+ldi 2 r2
+1:
+sub r2 r1 r2
+cmp 0 r2
+jgt 1b
+jeq 1f
+nop
+1:
+hlt
+").unwrap();
+
+        assert_eq!(program[4] & OPERAND_MASK, 2);
+        assert_eq!(program[8] & OPERAND_MASK, 6);
+        assert_eq!(program[9] & OPERAND_MASK, 11);
+
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+        cpu.load_program(&program);
+
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers()[0..3], [0, 1, 0]);
+    }
+
+    #[test]
+    fn ram_addresses_must_fit_the_address_field() {
+        assert_eq!(assemble("sto 63 r0\n").unwrap(), [(0b1001 << 18) | (63 << 2)]);
+
+        let err = assemble("nop\nsto 64 r0\n").unwrap_err();
+
+        assert_eq!(err.line, 2);
+        assert_eq!(err.kind, ErrorKind::OutOfRange { operand: "64".to_string(), width: RAM_ADDRESS_BITS });
+    }
+
+    #[test]
+    fn pic_makes_near_jumps_relative_and_leaves_far_ones_absolute() {
+        let options = AssembleOptions { pic: true, ..AssembleOptions::default() };
+
+        let near = assemble_with("jmp done\nnop\ndone:\nhlt\n", &options).unwrap();
+
+        assert_eq!(near[0], (JR_OPCODE << 18) | 2);
+
+        let far = assemble_with(&format!("jmp done\n{}done:\nhlt\n", "nop\n".repeat(20)), &options).unwrap();
+
+        assert_eq!(far[0], (0b0101 << 18) | 21);
+        assert_eq!(far, assemble(&format!("jmp done\n{}done:\nhlt\n", "nop\n".repeat(20))).unwrap());
+    }
+
+    #[test]
+    fn a_define_selects_the_conditional_block() {
+        let source = "
+ldi 1 r0
+.if DEBUG
+sto 62 r0
+.if VERBOSE
+sto 62 r0
+.endif
+.else
+nop
+.endif
+hlt
+";
+        let debug = AssembleOptions { defines: HashMap::from([("DEBUG".to_string(), 1)]), ..AssembleOptions::default() };
+
+        assert_eq!(assemble(source).unwrap(), assemble("ldi 1 r0\nnop\nhlt\n").unwrap());
+        assert_eq!(assemble_with(source, &debug).unwrap(), assemble("ldi 1 r0\nsto 62 r0\nhlt\n").unwrap());
+    }
+
+    #[test]
+    fn an_unclosed_if_is_rejected() {
+        let err = assemble("nop\n.if DEBUG\nnop\n").unwrap_err();
+
+        assert_eq!((err.line, err.kind), (2, ErrorKind::UnbalancedConditional(".if")));
+    }
+
+    #[test]
+    fn a_parsed_instruction_encodes_like_the_assembler() {
+        let lines = [
+            "nop", "hlt",
+            "jmp 7", "jr -3", "jeq 7", "jgt 7", "jlt 7", "jge 7", "jle 7", "jne 7",
+            "neg r1", "rdpc r3",
+            "ldi 300 r1", "cmp 5 r2", "sto 40 r3", "lod r0 40", "lea r1 40", "ldx r2 r3", "stx r3 r2", "bit r1 31",
+            "add r1 r2 r3", "sub r1 r2 r3", "mul r1 r2 r3"
+        ];
+
+        for line in lines {
+            let instruction: Instruction = line.parse().unwrap_or_else(|err| panic!("{}: {}", line, err));
+
+            assert_eq!(instruction.encoding(), assemble(line).unwrap()[0], "{}", line);
+            assert_eq!(Instruction::from_word(instruction.encoding()), instruction);
+        }
+
+        assert_eq!("".parse::<Instruction>(), Err(ParseInstructionError::Empty));
+        assert_eq!("div r1 r2 r3".parse::<Instruction>(), Err(ParseInstructionError::UnknownMnemonic("div".to_string())));
+        assert_eq!("jmp done".parse::<Instruction>(), Err(ParseInstructionError::NeedsProgram("jmp done".to_string())));
+    }
+
+    #[test]
+    fn the_basic_profile_assembles_basic_instructions() {
+        let options = AssembleOptions { profile: Some(IsaProfile::basic()), ..AssembleOptions::default() };
+
+        assert_eq!(assemble_with("ldi 5 r0\nadd r0 r0 r1\nhlt\n", &options).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn a_profile_rejects_an_instruction_outside_it() {
+        let options = AssembleOptions { profile: Some(IsaProfile::basic()), ..AssembleOptions::default() };
+        let err = assemble_with("ldi 5 r0\nmul r0 r0 r1\nhlt\n", &options).unwrap_err();
+
+        assert_eq!(err.line, 2);
+        assert_eq!(err.kind, ErrorKind::NotInProfile { mnemonic: "mul".to_string(), profile: "basic".to_string() });
+        assert_eq!(err.kind.to_string(), "'mul' is not part of the 'basic' instruction profile");
+    }
+
+    #[test]
+    fn a_custom_profile_allows_exactly_its_mnemonics() {
+        assert_eq!(assemble("ldi 5 r0\nmul r0 r0 r1\nhlt\n").unwrap().len(), 3);
+
+        let arithmetic = IsaProfile::new("arithmetic", &["ldi", "add", "mul", "hlt"]);
+
+        assert!(arithmetic.allows(33));
+        assert!(!arithmetic.allows(3));
+    }
+
+    #[test]
+    fn an_assembled_program_exposes_symbols_bytes_and_layout() {
+        let program = assemble_program("start:\nlod r0 value\n\njmp start\nvalue:\n.word 5\n").unwrap();
+
+        assert_eq!(program.symbols, HashMap::from([("start".to_string(), 0), ("value".to_string(), 2)]));
+        assert_eq!(program.as_bytes().len(), 12);
+        assert_eq!(program.as_bytes(), machine_code_as_bin_raw(&program.machine_code));
+        assert_eq!(program.source_map, [2, 4, 6]);
+        assert_eq!(program.memory_map.regions, [
+            Region { start: 0, len: 2, kind: RegionKind::Code },
+            Region { start: 2, len: 1, kind: RegionKind::Data }
+        ]);
+        assert_eq!(program.machine_code, assemble("start:\nlod r0 value\n\njmp start\nvalue:\n.word 5\n").unwrap());
+    }
+
+    #[test]
+    fn a_jump_into_data_fails_to_verify() {
+        // jeq 2, hlt, then a data word.
+        let program = [(6 << 18) | 2, 15 << 18, 5];
+        let memory_map = MemoryMap::from_kinds(&[RegionKind::Code, RegionKind::Code, RegionKind::Data]);
+
+        assert_eq!(verify(&program, &memory_map), Err((0, ErrorKind::JumpIntoData(2))));
+
+        // jeq 1 lands on the `hlt`.
+        let program = [(6 << 18) | 1, 15 << 18, 5];
+
+        assert_eq!(verify(&program, &memory_map), Ok(()));
+    }
+
+    #[test]
+    fn a_relative_jump_into_data_fails_to_verify() {
+        // A data word, a nop, then `jr -2` back onto the data.
+        let program = [0, 0, (22 << 18) | 0b11110];
+        let memory_map = MemoryMap::from_kinds(&[RegionKind::Data, RegionKind::Code, RegionKind::Code]);
+
+        assert_eq!(verify(&program, &memory_map), Err((2, ErrorKind::JumpIntoData(0))));
+    }
+
+    #[test]
+    fn data_that_decodes_as_a_jump_is_not_verified() {
+        // The first data word is `jmp 3`, which would point at the second.
+        let program = [15 << 18, (5 << 18) | 3, 0, 0];
+        let memory_map = MemoryMap::from_kinds(&[RegionKind::Code, RegionKind::Data, RegionKind::Code, RegionKind::Data]);
+
+        assert_eq!(verify(&program, &memory_map), Ok(()));
+    }
+
+    #[test]
+    fn assembling_a_jump_into_a_word_fails() {
+        let err = assemble("jeq table\nhlt\ntable:\n.word 5\n").unwrap_err();
+
+        assert_eq!(err.line, 1);
+        assert_eq!(err.kind, ErrorKind::JumpIntoData(2));
+        assert_eq!(err.kind.to_string(), "jump target 2 is data, not an instruction");
+    }
+
+    #[test]
+    fn a_jump_to_code_before_a_word_assembles() {
+        assert_eq!(assemble("jeq done\nnop\ndone:\nhlt\n.word 5\n").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn file_errors_say_what_went_wrong() {
+        let missing = temp_path("missing.asm");
+
+        assert!(matches!(
+            assemble_from_file(&missing, &AssembleOptions::default()),
+            Err(FileError::NotFound(path)) if path == missing
+        ));
+
+        let binary = temp_path("binary.asm");
+
+        std::fs::write(&binary, [0xff, 0xfe, 0x00, 0x3c]).unwrap();
+
+        let result = assemble_from_file(&binary, &AssembleOptions::default());
+
+        std::fs::remove_file(&binary).unwrap();
+
+        assert!(matches!(result, Err(FileError::NotUtf8(path)) if path == binary));
+    }
+
+    #[test]
+    fn crlf_source_assembles_like_lf() {
+        let lf = "start:  \nldi 3 r0 ; count\t\n\n.equ out 62\nsto out r0\njmp start\n.word 7\n";
+        let crlf = lf.replace('\n', "\r\n");
+
+        assert_eq!(assemble(&crlf).unwrap(), assemble(lf).unwrap());
+        assert_eq!(assemble_program(&crlf).unwrap().source_map, assemble_program(lf).unwrap().source_map);
+    }
+
+    #[test]
+    fn the_first_bad_line_is_reported() {
+        let source = "ldi 1 r0\nadd r0 r0 r1\n  ldi foo r1  \nfrob r1\nadd r1\n";
+        let err = assemble(source).unwrap_err();
+
+        assert_eq!(err, AssembleError { line: 3, text: "ldi foo r1".to_string(), kind: ErrorKind::InvalidImmediate("foo".to_string()) });
+        assert_eq!(err.to_string(), "line 3: operand 'foo' is not a valid immediate");
+
+        let kind = |source: &str| assemble(source).unwrap_err().kind;
+
+        assert_eq!(kind("frob r1\n"), ErrorKind::UnknownMnemonic("frob".to_string()));
+        assert_eq!(kind("add r1\n"), ErrorKind::WrongOperandCount { expected: 3, found: 1 });
+        assert_eq!(kind("ldi 65536 r0\n"), ErrorKind::OutOfRange { operand: "65536".to_string(), width: 16 });
+        assert_eq!(kind("ldi 1 r4\n"), ErrorKind::OutOfRange { operand: "r4".to_string(), width: 2 });
+        assert_eq!(kind("ldi 1 rx\n"), ErrorKind::InvalidRegister("rx".to_string()));
+    }
+
+    #[test]
+    fn labels_resolve_to_instruction_addresses() {
+        let labelled = assemble("ldi 3 r0\nldi 1 r1\nloop: sub r0 r1 r0\ncmp 0 r0\njgt loop\njmp end\nend:\nhlt\n").unwrap();
+        let numeric = assemble("ldi 3 r0\nldi 1 r1\nsub r0 r1 r0\ncmp 0 r0\njgt 2\njmp 6\nhlt\n").unwrap();
+
+        assert_eq!(labelled, numeric);
+        assert_eq!(run(&labelled).1, HaltReason::Halted);
+
+        let err = assemble("nop\njmp nowhere\n").unwrap_err();
+
+        assert_eq!((err.line, err.kind), (2, ErrorKind::Link(LinkError::UndefinedSymbol("nowhere".to_string()))));
+
+        let err = assemble("twice:\nnop\ntwice:\nhlt\n").unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::Link(LinkError::DuplicateSymbol("twice".to_string())));
+    }
+}
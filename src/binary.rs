@@ -0,0 +1,439 @@
+use std::fmt;
+
+/// On-disk representations of a program's machine code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryFormat {
+    /// Each word as 4 little-endian bytes, nothing else.
+    Raw,
+    /// Each word as 4 big-endian bytes, nothing else.
+    RawBigEndian,
+    /// A little-endian word count followed by the raw words.
+    Headered,
+    /// Text, one word per line as 8 hex digits.
+    Hex,
+    /// Intel HEX records holding the raw little-endian bytes.
+    IntelHex,
+    /// A C header declaring the words as a `uint32_t` array, for firmware.
+    CHeader
+}
+
+impl BinaryFormat {
+    pub fn from_name(name: &str) -> Option<BinaryFormat> {
+        match name {
+            "raw" => Some(BinaryFormat::Raw),
+            "raw-be" => Some(BinaryFormat::RawBigEndian),
+            "headered" => Some(BinaryFormat::Headered),
+            "hex" => Some(BinaryFormat::Hex),
+            "ihex" => Some(BinaryFormat::IntelHex),
+            "c-header" => Some(BinaryFormat::CHeader),
+            _ => None
+        }
+    }
+}
+
+/// Byte order of a word written as bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    /// Least significant byte first, as in every binary format here but `raw-be`.
+    #[default]
+    Little,
+    Big
+}
+
+impl Endianness {
+    pub fn word_bytes(self, word: u32) -> [u8; 4] {
+        match self {
+            Endianness::Little => word.to_le_bytes(),
+            Endianness::Big => word.to_be_bytes()
+        }
+    }
+
+    pub fn word_from_bytes(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endianness::Little => u32::from_le_bytes(bytes),
+            Endianness::Big => u32::from_be_bytes(bytes)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FormatError {
+    /// A headered binary too short to hold its word count.
+    MissingHeader,
+    /// The header's word count doesn't match the number of words that follow it.
+    LengthMismatch { expected: usize, found: usize },
+    /// A line of hex text isn't a valid word.
+    InvalidHex { line: usize, text: String },
+    /// A line isn't a well-formed Intel HEX record.
+    InvalidRecord { line: usize },
+    /// An Intel HEX record's checksum doesn't match its contents.
+    BadChecksum { line: usize },
+    /// Raw words don't fill the data; this many bytes are left after the last whole word.
+    TrailingBytes(usize),
+    /// A C header with no `{ ... }` array initializer.
+    MissingArray
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::MissingHeader => write!(f, "binary is too short to have a header"),
+            FormatError::LengthMismatch { expected, found } => {
+                write!(f, "header says {} words but {} follow it", expected, found)
+            }
+            FormatError::InvalidHex { line, text } => {
+                write!(f, "line {}: '{}' is not a hex word", line, text)
+            }
+            FormatError::InvalidRecord { line } => write!(f, "line {}: not a valid Intel HEX record", line),
+            FormatError::BadChecksum { line } => write!(f, "line {}: Intel HEX checksum mismatch", line),
+            FormatError::TrailingBytes(count) => {
+                write!(f, "length is not a multiple of 4 bytes, {} trailing bytes would be ignored", count)
+            }
+            FormatError::MissingArray => write!(f, "no '{{ ... }}' array of words found")
+        }
+    }
+}
+
+pub fn machine_code_as_bin_raw(program: &[u32]) -> Vec<u8> {
+    machine_code_as_bytes(program, Endianness::Little)
+}
+
+/// Reads raw little-endian words, failing if the bytes don't end on a word boundary.
+pub fn bin_raw_as_machine_code(bytes: &[u8]) -> Result<Vec<u32>, FormatError> {
+    bytes_as_machine_code(bytes, Endianness::Little)
+}
+
+pub fn machine_code_as_bytes(program: &[u32], endianness: Endianness) -> Vec<u8> {
+    program.iter().flat_map(|&word| endianness.word_bytes(word)).collect()
+}
+
+/// Reads raw words in the given byte order, failing if the bytes don't end on a
+/// word boundary.
+pub fn bytes_as_machine_code(bytes: &[u8], endianness: Endianness) -> Result<Vec<u32>, FormatError> {
+    let chunks = bytes.chunks_exact(4);
+
+    if !chunks.remainder().is_empty() {
+        return Err(FormatError::TrailingBytes(chunks.remainder().len()));
+    }
+
+    Ok(chunks.map(|chunk| endianness.word_from_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect())
+}
+
+pub fn machine_code_as_hex(program: &[u32]) -> String {
+    program.iter().map(|word| format!("{:08x}\n", word)).collect()
+}
+
+pub fn hex_as_machine_code(text: &str) -> Result<Vec<u32>, FormatError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            u32::from_str_radix(line.trim(), 16).map_err(|_| FormatError::InvalidHex {
+                line: i + 1,
+                text: line.trim().to_string()
+            })
+        })
+        .collect()
+}
+
+/// A C header declaring `program` as `static const uint32_t program[]`, four words
+/// to a line.
+pub fn machine_code_as_c_header(program: &[u32]) -> String {
+    let mut output = String::from("#include <stdint.h>\n\n");
+
+    output.push_str(&format!("static const uint32_t program[{}] = {{\n", program.len()));
+
+    for chunk in program.chunks(4) {
+        let words: Vec<String> = chunk.iter().map(|word| format!("0x{:08x},", word)).collect();
+
+        output.push_str(&format!("    {}\n", words.join(" ")));
+    }
+
+    output.push_str("};\n");
+
+    output
+}
+
+/// Reads the words of the first `{ ... }` array in a C header, as written by
+/// `machine_code_as_c_header`. Words may be hex (`0x`) or decimal.
+pub fn c_header_as_machine_code(text: &str) -> Result<Vec<u32>, FormatError> {
+    let start = text.find('{').ok_or(FormatError::MissingArray)?;
+    let end = start + text[start..].find('}').ok_or(FormatError::MissingArray)?;
+
+    text[start + 1..end]
+        .split(',')
+        .map(str::trim)
+        .filter(|term| !term.is_empty())
+        .map(|term| {
+            let parsed = match term.strip_prefix("0x").or_else(|| term.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => term.parse()
+            };
+
+            // Count lines up to the word for the error.
+            let offset = term.as_ptr() as usize - text.as_ptr() as usize;
+
+            parsed.map_err(|_| FormatError::InvalidHex { line: text[..offset].lines().count(), text: term.to_string() })
+        })
+        .collect()
+}
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_END_OF_FILE: u8 = 0x01;
+const RECORD_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+/// The largest program `from_intel_hex` reads, in bytes. 1 MiB is far more than
+/// any RAM the machine is given, and keeps a corrupt or hostile address from
+/// making it allocate gigabytes.
+pub const MAX_INTEL_HEX_BYTES: usize = 1 << 20;
+
+/// Formats one Intel HEX record, appending its checksum.
+fn intel_hex_record(address: u16, record_type: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, record_type];
+
+    bytes.extend_from_slice(data);
+
+    let checksum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)).wrapping_neg();
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+    format!(":{}{:02X}\n", hex, checksum)
+}
+
+/// Encodes the program's little-endian bytes as Intel HEX, 16 bytes per record.
+pub fn to_intel_hex(program: &[u32]) -> String {
+    let bytes = machine_code_as_bin_raw(program);
+    let mut output = String::new();
+    let mut upper_address = 0;
+
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let address = i * 16;
+
+        if address >> 16 != upper_address {
+            upper_address = address >> 16;
+            let segment = (upper_address as u16).to_be_bytes();
+
+            output.push_str(&intel_hex_record(0, RECORD_EXTENDED_LINEAR_ADDRESS, &segment));
+        }
+
+        output.push_str(&intel_hex_record(address as u16, RECORD_DATA, chunk));
+    }
+
+    output.push_str(&intel_hex_record(0, RECORD_END_OF_FILE, &[]));
+
+    output
+}
+
+/// Decodes Intel HEX back into machine code, checking every record's checksum.
+/// Any gaps between records read as zero bytes. A data record reaching past
+/// `MAX_INTEL_HEX_BYTES` is invalid.
+pub fn from_intel_hex(text: &str) -> Result<Vec<u32>, FormatError> {
+    let mut bytes = Vec::new();
+    let mut upper_address = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let line_number = i + 1;
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = line
+            .strip_prefix(':')
+            .filter(|hex| hex.len() % 2 == 0 && hex.len() >= 10)
+            .and_then(|hex| {
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|j| u8::from_str_radix(&hex[j..j + 2], 16).ok())
+                    .collect::<Option<Vec<u8>>>()
+            })
+            .ok_or(FormatError::InvalidRecord { line: line_number })?;
+
+        let length = record[0] as usize;
+
+        if record.len() != length + 5 {
+            return Err(FormatError::InvalidRecord { line: line_number });
+        }
+
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(FormatError::BadChecksum { line: line_number });
+        }
+
+        let address = upper_address + ((record[1] as usize) << 8 | record[2] as usize);
+        let data = &record[4..4 + length];
+
+        match record[3] {
+            RECORD_DATA if address + length > MAX_INTEL_HEX_BYTES => {
+                return Err(FormatError::InvalidRecord { line: line_number });
+            }
+            RECORD_DATA => {
+                if bytes.len() < address + length {
+                    bytes.resize(address + length, 0);
+                }
+
+                bytes[address..address + length].copy_from_slice(data);
+            }
+            RECORD_END_OF_FILE => break,
+            RECORD_EXTENDED_LINEAR_ADDRESS if length == 2 => {
+                upper_address = ((data[0] as usize) << 8 | data[1] as usize) << 16;
+            }
+            _ => return Err(FormatError::InvalidRecord { line: line_number })
+        }
+    }
+
+    // Round up to whole words.
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+
+    bin_raw_as_machine_code(&bytes)
+}
+
+/// Serializes `program` in the given format.
+pub fn encode(format: BinaryFormat, program: &[u32]) -> Vec<u8> {
+    match format {
+        BinaryFormat::Raw => machine_code_as_bin_raw(program),
+        BinaryFormat::RawBigEndian => machine_code_as_bytes(program, Endianness::Big),
+        BinaryFormat::Headered => {
+            let mut bytes = (program.len() as u32).to_le_bytes().to_vec();
+
+            bytes.extend(machine_code_as_bin_raw(program));
+
+            bytes
+        }
+        BinaryFormat::Hex => machine_code_as_hex(program).into_bytes(),
+        BinaryFormat::IntelHex => to_intel_hex(program).into_bytes(),
+        BinaryFormat::CHeader => machine_code_as_c_header(program).into_bytes()
+    }
+}
+
+/// Reads a program serialized in the given format.
+pub fn decode(format: BinaryFormat, bytes: &[u8]) -> Result<Vec<u32>, FormatError> {
+    match format {
+        BinaryFormat::Raw => bin_raw_as_machine_code(bytes),
+        BinaryFormat::RawBigEndian => bytes_as_machine_code(bytes, Endianness::Big),
+        BinaryFormat::Headered => {
+            let words = bin_raw_as_machine_code(bytes)?;
+            let (&expected, program) = words.split_first().ok_or(FormatError::MissingHeader)?;
+
+            if expected as usize != program.len() {
+                return Err(FormatError::LengthMismatch { expected: expected as usize, found: program.len() });
+            }
+
+            Ok(program.to_vec())
+        }
+        BinaryFormat::Hex => hex_as_machine_code(&String::from_utf8_lossy(bytes)),
+        BinaryFormat::IntelHex => from_intel_hex(&String::from_utf8_lossy(bytes)),
+        BinaryFormat::CHeader => c_header_as_machine_code(&String::from_utf8_lossy(bytes))
+    }
+}
+
+/// Formats bytes as a hexdump: 16 bytes per line, each line starting with its offset
+/// and ending with the printable bytes as ASCII (`.` for anything else).
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut output = String::new();
+
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+
+        output.push_str(&format!("{:08x}  {:<47}  |{}|\n", line * 16, hex.join(" "), ascii));
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: [u32; 5] = [0x0004_0005, 0x0009_0001, 0x0018_0000, 0x0020_1234, 0xffff_ffff];
+
+    #[test]
+    fn raw_converted_to_hex_and_back_keeps_the_machine_code() {
+        let raw = encode(BinaryFormat::Raw, &PROGRAM);
+        let hex = encode(BinaryFormat::Hex, &decode(BinaryFormat::Raw, &raw).unwrap());
+
+        assert_eq!(decode(BinaryFormat::Hex, &hex).unwrap(), PROGRAM);
+        assert_eq!(encode(BinaryFormat::Raw, &decode(BinaryFormat::Hex, &hex).unwrap()), raw);
+    }
+
+    #[test]
+    fn raw_big_endian_puts_the_high_byte_first() {
+        let bytes = encode(BinaryFormat::RawBigEndian, &[0x1122_3344]);
+
+        assert_eq!(bytes, [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(encode(BinaryFormat::Raw, &[0x1122_3344]), [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(decode(BinaryFormat::RawBigEndian, &bytes).unwrap(), [0x1122_3344]);
+    }
+
+    #[test]
+    fn a_c_header_declares_the_words_and_reads_back() {
+        let header = machine_code_as_c_header(&PROGRAM);
+
+        assert!(header.contains("static const uint32_t program[5] = {"));
+        assert!(header.contains("0x00040005, 0x00090001,"));
+        assert_eq!(c_header_as_machine_code(&header).unwrap(), PROGRAM);
+        assert_eq!(c_header_as_machine_code("int x;"), Err(FormatError::MissingArray));
+    }
+
+    #[test]
+    fn every_format_is_found_by_name_and_round_trips() {
+        for name in ["raw", "raw-be", "headered", "hex", "ihex", "c-header"] {
+            let format = BinaryFormat::from_name(name).unwrap();
+
+            assert_eq!(decode(format, &encode(format, &PROGRAM)).unwrap(), PROGRAM, "{}", name);
+        }
+
+        assert_eq!(BinaryFormat::from_name("elf"), None);
+    }
+
+    #[test]
+    fn hexdump_shows_offsets_and_printable_bytes() {
+        let dump = hexdump(b"Hello, cpusim!\x00\x01ABC");
+        let lines: Vec<&str> = dump.lines().collect();
+
+        assert_eq!(lines, [
+            "00000000  48 65 6c 6c 6f 2c 20 63 70 75 73 69 6d 21 00 01  |Hello, cpusim!..|",
+            "00000010  41 42 43                                         |ABC|"
+        ]);
+    }
+
+    #[test]
+    fn an_intel_hex_record_with_a_bad_checksum_is_rejected() {
+        let text = to_intel_hex(&PROGRAM);
+        let (first, rest) = text.split_once('\n').unwrap();
+        let (record, checksum) = first.split_at(first.len() - 2);
+        let wrong = u8::from_str_radix(checksum, 16).unwrap() ^ 0xff;
+
+        assert_eq!(from_intel_hex(&text).unwrap(), PROGRAM);
+        assert_eq!(from_intel_hex(&format!("{}{:02X}\n{}", record, wrong, rest)), Err(FormatError::BadChecksum { line: 1 }));
+    }
+
+    #[test]
+    fn raw_bytes_round_trip_and_reject_a_partial_word() {
+        let program = crate::assembler::assemble("ldi 5 r0\nsto 62 r0\nhlt\n").unwrap();
+        let bytes = machine_code_as_bin_raw(&program);
+
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(bin_raw_as_machine_code(&bytes), Ok(program));
+        assert_eq!(bin_raw_as_machine_code(&bytes[..10]), Err(FormatError::TrailingBytes(2)));
+        assert_eq!(
+            FormatError::TrailingBytes(2).to_string(),
+            "length is not a multiple of 4 bytes, 2 trailing bytes would be ignored"
+        );
+    }
+
+    #[test]
+    fn an_intel_hex_record_past_the_largest_program_is_rejected() {
+        let text = [
+            intel_hex_record(0, RECORD_EXTENDED_LINEAR_ADDRESS, &[0xFF, 0xFF]),
+            intel_hex_record(0xFFF0, RECORD_DATA, &[1, 2, 3, 4]),
+            intel_hex_record(0, RECORD_END_OF_FILE, &[])
+        ]
+        .concat();
+
+        assert_eq!(from_intel_hex(&text), Err(FormatError::InvalidRecord { line: 2 }));
+    }
+}
@@ -0,0 +1,278 @@
+//! A line-based interactive debugger, started with `cpusim debug <file>` or
+//! `cpusim <file> --interactive`. It reads one command per line and drives the
+//! processor through its stepping and inspection methods.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use crate::{disassemble, describe_flag, HaltReason, Processor, StepResult, FLAG_CARRY};
+
+const HELP: &str = "\
+commands:
+  step [n]            execute n instructions (default 1)
+  continue            run until a breakpoint or the machine stops
+  break <addr>        set a breakpoint
+  delete <addr>       remove a breakpoint
+  regs                show registers, the flag register and the program counter
+  mem <addr> [n]      show n words of RAM (default 1)
+  disasm [addr] [n]   disassemble n words (default 8 from the program counter)
+  help                show this message
+  quit                leave the debugger";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Step(usize),
+    Continue,
+    Break(usize),
+    Delete(usize),
+    Registers,
+    Memory { address: usize, count: usize },
+    Disassemble { address: Option<usize>, count: usize },
+    Help,
+    Quit
+}
+
+/// Parses a decimal or `0x`-prefixed hex number.
+fn parse_number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse()
+    };
+
+    parsed.map_err(|_| format!("'{}' is not a number", text))
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Command, String> {
+        let terms: Vec<&str> = line.split_whitespace().collect();
+
+        let argument = |i: usize| terms.get(i).map(|term| parse_number(term)).transpose();
+        let required = |i: usize| argument(i)?.ok_or_else(|| format!("'{}' needs an address", terms[0]));
+
+        let command = match terms.first().copied() {
+            Some("step" | "s") => Command::Step(argument(1)?.unwrap_or(1)),
+            Some("continue" | "c") => Command::Continue,
+            Some("break" | "b") => Command::Break(required(1)?),
+            Some("delete" | "d") => Command::Delete(required(1)?),
+            Some("regs" | "r") => Command::Registers,
+            Some("mem" | "m") => Command::Memory { address: required(1)?, count: argument(2)?.unwrap_or(1) },
+            Some("disasm" | "x") => Command::Disassemble { address: argument(1)?, count: argument(2)?.unwrap_or(8) },
+            Some("help" | "h") => Command::Help,
+            Some("quit" | "q") => Command::Quit,
+            Some(other) => return Err(format!("unknown command '{}' (try 'help')", other)),
+            None => return Err("empty command".to_string())
+        };
+
+        Ok(command)
+    }
+}
+
+pub struct Debugger {
+    cpu: Processor,
+    breakpoints: BTreeSet<usize>
+}
+
+impl Debugger {
+    pub fn new(cpu: Processor) -> Debugger {
+        Debugger { cpu, breakpoints: BTreeSet::new() }
+    }
+
+    /// One line showing the word at `address` and its disassembly, marking the
+    /// program counter and any breakpoint.
+    fn listing_line(&self, address: usize) -> String {
+        let word = self.cpu.ram()[address];
+        let marker = if address == self.cpu.program_counter() { '>' } else { ' ' };
+        let breakpoint = if self.breakpoints.contains(&address) { '*' } else { ' ' };
+
+        format!("{}{}{:>3}: {:08x}  {}", marker, breakpoint, address, word, disassemble(word))
+    }
+
+    fn stopped(&self, reason: HaltReason) -> String {
+        format!("stopped: {:?} at {}", reason, self.cpu.program_counter())
+    }
+
+    fn check_address(&self, address: usize) -> Result<usize, String> {
+        if address < self.cpu.ram().len() {
+            Ok(address)
+        }
+        else {
+            Err(format!("address {} is outside RAM (0-{})", address, self.cpu.ram().len() - 1))
+        }
+    }
+
+    /// Carries out one command and returns what it has to say. `Quit` is left to
+    /// the caller.
+    pub fn execute(&mut self, command: Command) -> Result<String, String> {
+        let output = match command {
+            Command::Step(n) => match self.cpu.step_n(n) {
+                HaltReason::StepLimit => self.listing_line(self.cpu.program_counter()),
+                reason => self.stopped(reason)
+            },
+            Command::Continue => loop {
+                if let StepResult::Stopped(reason) = self.cpu.step() {
+                    break self.stopped(reason);
+                }
+
+                if self.breakpoints.contains(&self.cpu.program_counter()) {
+                    break format!("breakpoint\n{}", self.listing_line(self.cpu.program_counter()));
+                }
+            },
+            Command::Break(address) => {
+                self.breakpoints.insert(self.check_address(address)?);
+                format!("breakpoint set at {}", address)
+            }
+            Command::Delete(address) => {
+                if !self.breakpoints.remove(&address) {
+                    return Err(format!("no breakpoint at {}", address));
+                }
+
+                format!("breakpoint removed at {}", address)
+            }
+            Command::Registers => format!(
+                "registers: {:?}\nflags: {}{} ({:#06b})\npc: {}{}",
+                self.cpu.registers(),
+                describe_flag(self.cpu.flags()),
+                if self.cpu.flags() & FLAG_CARRY != 0 { ", carry" } else { "" },
+                self.cpu.flags(),
+                self.cpu.program_counter(),
+                if self.cpu.is_halted() { " (halted)" } else { "" }
+            ),
+            Command::Memory { address, count } => {
+                let start = self.check_address(address)?;
+                let end = start.saturating_add(count).min(self.cpu.ram().len());
+
+                (start..end)
+                    .map(|address| format!("{:>3}: {:08x}  {}", address, self.cpu.ram()[address], self.cpu.ram()[address]))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }
+            Command::Disassemble { address, count } => {
+                let start = self.check_address(address.unwrap_or(self.cpu.program_counter()))?;
+                let end = start.saturating_add(count).min(self.cpu.ram().len());
+
+                (start..end).map(|address| self.listing_line(address)).collect::<Vec<String>>().join("\n")
+            }
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new()
+        };
+
+        Ok(output)
+    }
+
+    /// Reads commands from `input` until `quit` or end of input, writing replies and
+    /// errors to `output`.
+    pub fn run(&mut self, input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.listing_line(self.cpu.program_counter()))?;
+
+        for line in input.lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match line.parse::<Command>() {
+                Ok(Command::Quit) => break,
+                Ok(command) => match self.execute(command) {
+                    Ok(reply) => writeln!(output, "{}", reply)?,
+                    Err(message) => writeln!(output, "error: {}", message)?
+                },
+                Err(message) => writeln!(output, "error: {}", message)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, Verbosity};
+
+    fn debugger(source: &str) -> Debugger {
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).build();
+
+        cpu.load_program(&assembler::assemble(source).unwrap());
+
+        Debugger::new(cpu)
+    }
+
+    /// The debugger's reply to each line of `script`, as one string.
+    fn session(source: &str, script: &str) -> String {
+        let mut output = Vec::new();
+
+        debugger(source).run(script.as_bytes(), &mut output).unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn commands_parse_with_their_defaults() {
+        assert_eq!("step".parse(), Ok(Command::Step(1)));
+        assert_eq!("s 4".parse(), Ok(Command::Step(4)));
+        assert_eq!("break 0x10".parse(), Ok(Command::Break(16)));
+        assert_eq!("mem 40".parse(), Ok(Command::Memory { address: 40, count: 1 }));
+        assert_eq!("disasm".parse(), Ok(Command::Disassemble { address: None, count: 8 }));
+        assert_eq!("x 2 3".parse(), Ok(Command::Disassemble { address: Some(2), count: 3 }));
+        assert_eq!("q".parse(), Ok(Command::Quit));
+
+        assert!("break".parse::<Command>().is_err());
+        assert!("mem ten".parse::<Command>().is_err());
+        assert!("frobnicate".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn a_scripted_session_steps_and_inspects() {
+        // Nothing after `quit` runs.
+        let output = session("ldi 7 r0\nsto 40 r0\nhlt\n", "step 2\nregs\nbogus\nmem 40\nstep\nquit\nstep\n");
+
+        assert_eq!(output, "\
+>   0: 0004001c  LOAD_IMMED 7 R0
+>   2: 003c0000  HALT
+registers: [7, 0, 0, 0]
+flags: clear (0b0000)
+pc: 2
+error: unknown command 'bogus' (try 'help')
+ 40: 00000007  7
+stopped: Halted at 2
+");
+    }
+
+    #[test]
+    fn continue_stops_at_each_breakpoint_until_the_machine_halts() {
+        let source = "ldi 2 r0\nldi 1 r1\nloop:\nsub r0 r1 r0\nsto 40 r0\ncmp 0 r0\njgt loop\nhlt\n";
+        let output = session(source, "b 3\nc\nm 40\nc\nm 40\nd 3\nc\nb 99\nd 3\n");
+
+        assert_eq!(output, "\
+>   0: 00040008  LOAD_IMMED 2 R0
+breakpoint set at 3
+breakpoint
+>*  3: 002400a0  STORE 40 R0
+ 40: 00000000  0
+breakpoint
+>*  3: 002400a0  STORE 40 R0
+ 40: 00000001  1
+breakpoint removed at 3
+stopped: Halted at 6
+error: address 99 is outside RAM (0-63)
+error: no breakpoint at 3
+");
+    }
+
+    #[test]
+    fn a_huge_count_stops_at_the_end_of_ram() {
+        let mut debugger = debugger("hlt\n");
+
+        let command = "mem 62 18446744073709551615".parse().unwrap();
+
+        assert_eq!(debugger.execute(command), Ok(" 62: 00000000  0\n 63: 00000000  0".to_string()));
+
+        let command = "disasm 63 18446744073709551615".parse().unwrap();
+
+        assert_eq!(debugger.execute(command), Ok("   63: 00000000  NO-OP".to_string()));
+    }
+}
@@ -0,0 +1,334 @@
+//! The operand layout of every instruction. `encode` and `decode` convert between
+//! machine code words and typed operands using these layouts, and `fields` labels
+//! a word's bits for display.
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::OPERAND_MASK;
+
+/// One bit-field of the DATA part of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldSpec {
+    pub label: &'static str,
+    pub shift: u32,
+    pub width: u32,
+    /// Holds a two's complement value, like `jr`'s offset.
+    pub signed: bool
+}
+
+const fn field(label: &'static str, shift: u32, width: u32) -> FieldSpec {
+    FieldSpec { label, shift, width, signed: false }
+}
+
+const REGISTER: FieldSpec = field("register", 0, 2);
+const DESTINATION: FieldSpec = field("destination", 0, 2);
+const IMMEDIATE: FieldSpec = field("immediate", 2, 16);
+const SOURCE_A: FieldSpec = field("source a", 4, 2);
+const SOURCE_B: FieldSpec = field("source b", 2, 2);
+const SOURCE: FieldSpec = field("source", 2, 2);
+const ADDRESS_REGISTER: FieldSpec = field("address register", 2, 2);
+const JUMP_ADDRESS: FieldSpec = field("address", 0, 5);
+const RAM_ADDRESS: FieldSpec = field("address", 2, 6);
+const BIT_INDEX: FieldSpec = field("bit index", 2, 5);
+const OFFSET: FieldSpec = FieldSpec { label: "offset", shift: 0, width: 5, signed: true };
+
+/// The operand layouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `nop`, `hlt`, `dbg`
+    None,
+    /// `ldi`, `cmp`
+    ImmediateRegister,
+    /// `add`, `sub`, `mul` and the bitwise operations
+    ThreeRegisters,
+    /// `jmp` and the conditional jumps
+    Jump,
+    /// `sto`, `lod`, `lea`
+    AddressRegister,
+    /// `neg`, `not`, `rdpc`
+    Register,
+    /// `bit`
+    BitTest,
+    /// `jr`
+    Relative,
+    /// The conditional moves
+    TwoRegisters,
+    /// `ldx`, `stx`
+    Indexed
+}
+
+impl Encoding {
+    /// The layout used by `opcode`, or `None` if no instruction has that opcode.
+    pub fn of(opcode: u32) -> Option<Encoding> {
+        let encoding = match opcode {
+            0 | 15 | 27 => Encoding::None,
+            1 | 4 => Encoding::ImmediateRegister,
+            2 | 3 | 11..=14 | 29 | 33 => Encoding::ThreeRegisters,
+            5..=8 | 18..=20 | 28 => Encoding::Jump,
+            9 | 10 | 23 => Encoding::AddressRegister,
+            16 | 21 | 30 => Encoding::Register,
+            17 => Encoding::BitTest,
+            22 => Encoding::Relative,
+            24..=26 => Encoding::TwoRegisters,
+            31 | 32 => Encoding::Indexed,
+            _ => return None
+        };
+
+        Some(encoding)
+    }
+
+    /// The fields, from the most significant down.
+    pub fn fields(self) -> &'static [FieldSpec] {
+        match self {
+            Encoding::None => &[],
+            Encoding::ImmediateRegister => &[IMMEDIATE, REGISTER],
+            Encoding::ThreeRegisters => &[SOURCE_A, SOURCE_B, DESTINATION],
+            Encoding::Jump => &[JUMP_ADDRESS],
+            Encoding::AddressRegister => &[RAM_ADDRESS, REGISTER],
+            Encoding::Register => &[REGISTER],
+            Encoding::BitTest => &[BIT_INDEX, REGISTER],
+            Encoding::Relative => &[OFFSET],
+            Encoding::TwoRegisters => &[SOURCE, DESTINATION],
+            Encoding::Indexed => &[ADDRESS_REGISTER, REGISTER]
+        }
+    }
+}
+
+/// Typed operands, one variant per `Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    None,
+    ImmediateRegister { immediate: u32, register: u32 },
+    ThreeRegisters { a: u32, b: u32, destination: u32 },
+    Jump { address: u32 },
+    AddressRegister { address: u32, register: u32 },
+    Register { register: u32 },
+    BitTest { index: u32, register: u32 },
+    Relative { offset: i32 },
+    TwoRegisters { source: u32, destination: u32 },
+    Indexed { address_register: u32, register: u32 }
+}
+
+impl Operands {
+    fn encoding(self) -> Encoding {
+        match self {
+            Operands::None => Encoding::None,
+            Operands::ImmediateRegister { .. } => Encoding::ImmediateRegister,
+            Operands::ThreeRegisters { .. } => Encoding::ThreeRegisters,
+            Operands::Jump { .. } => Encoding::Jump,
+            Operands::AddressRegister { .. } => Encoding::AddressRegister,
+            Operands::Register { .. } => Encoding::Register,
+            Operands::BitTest { .. } => Encoding::BitTest,
+            Operands::Relative { .. } => Encoding::Relative,
+            Operands::TwoRegisters { .. } => Encoding::TwoRegisters,
+            Operands::Indexed { .. } => Encoding::Indexed
+        }
+    }
+
+    /// The operand values in the order of `Encoding::fields`.
+    fn values(self) -> Vec<i64> {
+        match self {
+            Operands::None => vec![],
+            Operands::ImmediateRegister { immediate, register } => vec![immediate as i64, register as i64],
+            Operands::ThreeRegisters { a, b, destination } => vec![a as i64, b as i64, destination as i64],
+            Operands::Jump { address } => vec![address as i64],
+            Operands::AddressRegister { address, register } => vec![address as i64, register as i64],
+            Operands::Register { register } => vec![register as i64],
+            Operands::BitTest { index, register } => vec![index as i64, register as i64],
+            Operands::Relative { offset } => vec![offset as i64],
+            Operands::TwoRegisters { source, destination } => vec![source as i64, destination as i64],
+            Operands::Indexed { address_register, register } => vec![address_register as i64, register as i64]
+        }
+    }
+
+    /// The inverse of `values`.
+    fn from_values(encoding: Encoding, values: &[i64]) -> Operands {
+        let value = |i: usize| values[i] as u32;
+
+        match encoding {
+            Encoding::None => Operands::None,
+            Encoding::ImmediateRegister => Operands::ImmediateRegister { immediate: value(0), register: value(1) },
+            Encoding::ThreeRegisters => Operands::ThreeRegisters { a: value(0), b: value(1), destination: value(2) },
+            Encoding::Jump => Operands::Jump { address: value(0) },
+            Encoding::AddressRegister => Operands::AddressRegister { address: value(0), register: value(1) },
+            Encoding::Register => Operands::Register { register: value(0) },
+            Encoding::BitTest => Operands::BitTest { index: value(0), register: value(1) },
+            Encoding::Relative => Operands::Relative { offset: values[0] as i32 },
+            Encoding::TwoRegisters => Operands::TwoRegisters { source: value(0), destination: value(1) },
+            Encoding::Indexed => Operands::Indexed { address_register: value(0), register: value(1) }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    UnknownOpcode(u32),
+    /// The operands are for a different layout than the opcode uses.
+    WrongOperands { opcode: u32, expected: Encoding },
+    /// A value doesn't fit in its field.
+    FieldOutOfRange { label: &'static str, value: i64, width: u32 }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::UnknownOpcode(opcode) => write!(f, "no instruction has opcode {}", opcode),
+            EncodeError::WrongOperands { opcode, expected } => {
+                write!(f, "opcode {} takes {:?} operands", opcode, expected)
+            }
+            EncodeError::FieldOutOfRange { label, value, width } => {
+                write!(f, "{} {} doesn't fit in a {}-bit field", label, value, width)
+            }
+        }
+    }
+}
+
+/// Packs `opcode` and `operands` into a machine code word, checking that every value
+/// fits its field.
+pub fn encode(opcode: u32, operands: Operands) -> Result<u32, EncodeError> {
+    let expected = Encoding::of(opcode).ok_or(EncodeError::UnknownOpcode(opcode))?;
+
+    if operands.encoding() != expected {
+        return Err(EncodeError::WrongOperands { opcode, expected });
+    }
+
+    let mut word = opcode << 18;
+
+    for (spec, value) in expected.fields().iter().zip(operands.values()) {
+        let fits = if spec.signed {
+            (-(1 << (spec.width - 1))..1 << (spec.width - 1)).contains(&value)
+        }
+        else {
+            (0..1 << spec.width).contains(&value)
+        };
+
+        if !fits {
+            return Err(EncodeError::FieldOutOfRange { label: spec.label, value, width: spec.width });
+        }
+
+        word |= (value as u32 & ((1 << spec.width) - 1)) << spec.shift;
+    }
+
+    Ok(word)
+}
+
+/// Splits a machine code word into its opcode and typed operands, or `None` if no
+/// instruction has its opcode. Bits outside the opcode's fields are ignored.
+pub fn decode(word: u32) -> Option<(u32, Operands)> {
+    let opcode = word >> 18;
+    let operand = word & OPERAND_MASK;
+    let encoding = Encoding::of(opcode)?;
+
+    let values: Vec<i64> = encoding
+        .fields()
+        .iter()
+        .map(|spec| {
+            let raw = (operand >> spec.shift) & ((1 << spec.width) - 1);
+
+            if spec.signed {
+                (((raw << (32 - spec.width)) as i32) >> (32 - spec.width)) as i64
+            }
+            else {
+                raw as i64
+            }
+        })
+        .collect();
+
+    Some((opcode, Operands::from_values(encoding, &values)))
+}
+
+/// A bit-field of a word: the bit positions it covers (0 is the least significant),
+/// its label and the raw bits it holds.
+pub type FieldValue = (Range<u32>, &'static str, u32);
+
+/// Breaks `word` into labelled bit-fields for display, the opcode first and then
+/// the operand fields of its layout. A word with an unknown opcode has just the
+/// opcode field.
+pub fn fields(word: u32) -> Vec<FieldValue> {
+    let opcode = word >> 18;
+    let mut fields = vec![(18..24, "opcode", opcode)];
+
+    if let Some(encoding) = Encoding::of(opcode) {
+        for spec in encoding.fields() {
+            let raw = (word >> spec.shift) & ((1 << spec.width) - 1);
+
+            fields.push((spec.shift..spec.shift + spec.width, spec.label, raw));
+        }
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{Processor, Verbosity};
+
+    #[test]
+    fn every_opcode_round_trips_through_decode_and_encode() {
+        let mut opcodes = 0;
+
+        for opcode in 0..64 {
+            let Some(encoding) = Encoding::of(opcode) else {
+                assert_eq!(decode(opcode << 18), None);
+                continue;
+            };
+
+            let used: u32 = encoding.fields().iter().map(|spec| ((1 << spec.width) - 1) << spec.shift).sum();
+
+            for pattern in [0, OPERAND_MASK, 0x15555, 0x2AAAA] {
+                let word = (opcode << 18) | (pattern & used);
+                let (decoded_opcode, operands) = decode(word).unwrap();
+
+                assert_eq!(decoded_opcode, opcode);
+                assert_eq!(encode(opcode, operands), Ok(word), "opcode {} pattern {:#x}", opcode, pattern);
+            }
+
+            opcodes += 1;
+        }
+
+        assert_eq!(opcodes, 34);
+    }
+
+    #[test]
+    fn a_value_too_wide_for_its_field_is_rejected() {
+        assert_eq!(
+            encode(1, Operands::ImmediateRegister { immediate: 1 << 16, register: 0 }),
+            Err(EncodeError::FieldOutOfRange { label: "immediate", value: 1 << 16, width: 16 })
+        );
+        assert_eq!(
+            encode(2, Operands::ThreeRegisters { a: 4, b: 0, destination: 0 }),
+            Err(EncodeError::FieldOutOfRange { label: "source a", value: 4, width: 2 })
+        );
+        assert_eq!(
+            encode(22, Operands::Relative { offset: 16 }),
+            Err(EncodeError::FieldOutOfRange { label: "offset", value: 16, width: 5 })
+        );
+        assert_eq!(encode(22, Operands::Relative { offset: -16 }), Ok((22 << 18) | 0b10000));
+        assert_eq!(encode(1, Operands::None), Err(EncodeError::WrongOperands { opcode: 1, expected: Encoding::ImmediateRegister }));
+        assert_eq!(encode(63, Operands::None), Err(EncodeError::UnknownOpcode(63)));
+    }
+
+    #[test]
+    fn an_ldi_breaks_down_into_opcode_immediate_and_register() {
+        let word = crate::assembler::assemble("ldi 300 r2").unwrap()[0];
+        let expected = vec![(18..24, "opcode", 1), (2..18, "immediate", 300), (0..2, "register", 2)];
+
+        assert_eq!(fields(word), expected);
+
+        // The step callback sees the same breakdown just before the instruction runs.
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).build();
+        let log = Rc::clone(&seen);
+
+        cpu.set_step_callback(Box::new(move |word, fields| log.borrow_mut().push((word, fields.to_vec()))));
+        cpu.load_program(&[word]);
+        cpu.step();
+
+        assert_eq!(*seen.borrow(), [(word, expected)]);
+    }
+}
@@ -0,0 +1,166 @@
+//! Small example programs for learning the instruction set, runnable with
+//! `cpusim example <name>`. Each one writes its result to the output port.
+
+/// Counts down from 5 to 1, printing each number.
+const COUNTDOWN: &str = "
+.equ out 62
+ldi 5 r0
+ldi 1 r1
+loop:
+sto out r0
+sub r0 r1 r0
+cmp 0 r0
+jgt loop
+hlt
+";
+
+/// Multiplies 6 by 7 by adding 6 to an accumulator seven times.
+const MULTIPLY: &str = "
+.equ out 62
+ldi 6 r0
+ldi 7 r1
+ldi 1 r3
+loop:
+add r2 r0 r2
+sub r1 r3 r1
+cmp 0 r1
+jgt loop
+sto out r2
+hlt
+";
+
+/// Copies three words from `source` to `dest`, then prints the last copied word.
+const MEMORY_COPY: &str = "
+.equ out 62
+lod r0 source0
+sto dest0 r0
+lod r0 source1
+sto dest1 r0
+lod r0 source2
+sto dest2 r0
+lod r1 dest2
+sto out r1
+hlt
+source0:
+.word 11
+source1:
+.word 22
+source2:
+.word 33
+dest0:
+.word 0
+dest1:
+.word 0
+dest2:
+.word 0
+";
+
+/// Finds the largest word of a list that ends in 0. With no register-to-register
+/// compare, each word races the current maximum down to zero: whichever reaches
+/// zero first is the smaller one. `lod` only takes a fixed address, so the loop
+/// walks the list by adding 4 to the address field of its own `lod` at `next`.
+const MAX_OF_ARRAY: &str = "
+.equ out 62
+ldi 1 r2
+next:
+lod r1 array
+cmp 0 r1
+jeq done
+ldi 0 r3
+add r1 r3 r3
+lod r0 max
+1:
+cmp 0 r1
+jeq 2f
+cmp 0 r0
+jeq 3f
+sub r0 r2 r0
+sub r1 r2 r1
+jmp 1b
+3:
+sto max r3
+2:
+lod r3 next
+ldi 4 r0
+add r3 r0 r3
+sto next r3
+jmp next
+done:
+lod r0 max
+sto out r0
+hlt
+array:
+.word 4
+.word 9
+.word 7
+.word 0
+max:
+.word 0
+";
+
+/// Counts the set bits of 182 (0b10110110) by testing the lowest bit and
+/// shifting it out until nothing is left.
+const POPCOUNT: &str = "
+.equ out 62
+ldi 182 r0
+ldi 1 r1
+loop:
+and r0 r1 r3
+add r2 r3 r2
+shr r0 r1 r0
+cmp 0 r0
+jgt loop
+sto out r2
+hlt
+";
+
+/// Every example, by name.
+pub const EXAMPLES: &[(&str, &str)] = &[
+    ("countdown", COUNTDOWN),
+    ("multiply", MULTIPLY),
+    ("memory-copy", MEMORY_COPY),
+    ("max-of-array", MAX_OF_ARRAY),
+    ("popcount", POPCOUNT)
+];
+
+/// Looks up an example's assembly source by name.
+pub fn find(name: &str) -> Option<&'static str> {
+    EXAMPLES.iter().find(|(example, _)| *example == name).map(|(_, source)| *source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, Clock, HaltReason, Processor, SharedBuffer, Verbosity};
+
+    #[test]
+    fn every_example_halts_with_its_result() {
+        let expected = [
+            ("countdown", "5\n4\n3\n2\n1\n"),
+            ("multiply", "42\n"),
+            ("memory-copy", "33\n"),
+            ("max-of-array", "9\n"),
+            ("popcount", "5\n")
+        ];
+
+        assert_eq!(EXAMPLES.len(), expected.len());
+
+        for (name, output) in expected {
+            let program = assembler::assemble(find(name).unwrap()).unwrap_or_else(|err| panic!("{}: {}", name, err));
+            let buffer = SharedBuffer::default();
+            let mut cpu = Processor::builder()
+                .verbosity(Verbosity::Quiet)
+                .clock(Clock::Immediate)
+                .cycle_limit(Some(1000))
+                .sink(Box::new(buffer.clone()))
+                .build();
+
+            cpu.load_program(&program);
+
+            assert_eq!(cpu.run(), HaltReason::Halted, "{}", name);
+            assert_eq!(buffer.contents(), output, "{}", name);
+        }
+
+        assert_eq!(find("nope"), None);
+    }
+}
@@ -0,0 +1,2508 @@
+//! A simulator for a small teaching CPU: the processor itself, plus an assembler,
+//! binary formats, a debugger and analysis tools built around it. The `cpusim`
+//! binary is a command-line front end to this library.
+
+pub mod assembler;
+pub mod binary;
+pub mod debugger;
+pub mod encoding;
+pub mod examples;
+pub mod lint;
+pub mod multicycle;
+pub mod pipeline;
+pub mod replay;
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use binary::Endianness;
+use encoding::FieldValue;
+
+pub struct Processor {
+    registers: Vec<u32>,
+    program_counter: usize,
+    ram: Vec<u32>,
+    flag_register: u32,
+    halt: bool,
+    output_format: OutputFormat,
+    verbosity: Verbosity,
+    /// Values waiting to be read from `INPUT_PORT`, oldest first.
+    input: VecDeque<u32>,
+    /// The xorshift state behind `RANDOM_PORT`; never 0.
+    random_state: u32,
+    /// Instructions left before `Trap::OutOfFuel`, or `None` for no limit.
+    fuel: Option<u64>,
+    /// Total instructions after which execution traps with `Trap::CycleLimit`, or
+    /// `None` for no limit.
+    cycle_limit: Option<u64>,
+    /// Where trace and output port text is written; stdout when empty.
+    sinks: RefCell<Vec<Box<dyn Write>>>,
+    flag_clear_policy: FlagClearPolicy,
+    memory_model: MemoryModel,
+    /// Instruction memory, only used in `MemoryModel::Harvard`.
+    rom: Vec<u32>,
+    /// Recent machine states, when loop detection is on.
+    loop_detector: Option<LoopDetector>,
+    /// Instructions executed so far.
+    cycles: u64,
+    /// Every flag register change so far, when the flag trace is on.
+    flag_trace: Option<Vec<FlagChange>>,
+    /// Every instruction executed so far, when the execution trace is on.
+    execution_trace: Option<Vec<TraceRecord>>,
+    /// Wall-clock time after which execution traps with `Trap::Timeout`.
+    deadline: Option<Instant>,
+    /// Pause after each cycle of `run`, to watch a program as it goes.
+    cycle_delay: Duration,
+    port_encoding: PortEncoding,
+    /// Called with each instruction word and its labelled bit-fields before it executes.
+    step_callback: Option<StepCallback>
+}
+
+pub type StepCallback = Box<dyn FnMut(u32, &[FieldValue])>;
+
+/// A RAM fill pattern that stands out in traces, see `Processor::fill_ram`.
+pub const POISON: u32 = 0xDEADBEEF;
+
+/// Instructions between checks of the wall-clock deadline, so the clock isn't
+/// read on every cycle.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Remembers hashes of the last few machine states. Seeing the same state twice
+/// means the program is going round a loop that changes nothing, so it can never
+/// stop. Only loops at most `capacity` instructions long are caught, and a hash
+/// collision could in principle report a loop that isn't there.
+struct LoopDetector {
+    capacity: usize,
+    history: VecDeque<u64>
+}
+
+impl LoopDetector {
+    fn new(capacity: usize) -> LoopDetector {
+        LoopDetector { capacity, history: VecDeque::with_capacity(capacity) }
+    }
+
+    fn seen(&self, state: u64) -> bool {
+        self.history.contains(&state)
+    }
+
+    /// Remembers `state`, forgetting the oldest one if the history is full.
+    fn record(&mut self, state: u64) {
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+
+        self.history.push_back(state);
+    }
+}
+
+/// The state an instruction started from, for describing its effect afterwards.
+struct StepSnapshot {
+    instruction: u32,
+    registers: Vec<u32>,
+    program_counter: usize,
+    flag_register: u32
+}
+
+/// What one cycle changed, for storing a state log compactly.
+struct StateDelta {
+    registers: Vec<u32>,
+    program_counter: usize,
+    flag_register: u32,
+    halt: bool,
+    /// The RAM cell a store wrote, with its new value.
+    ram: Option<(usize, u32)>
+}
+
+/// Whether code and data share one memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryModel {
+    /// Instructions are fetched from RAM, so stores can overwrite code.
+    #[default]
+    Shared,
+    /// Instructions are fetched from a separate ROM. The program image is loaded
+    /// into both ROM and RAM, so `lod` still sees `.word` data, but stores only
+    /// change RAM and can't alter the code that runs.
+    Harvard,
+}
+
+/// RAM size of `Processor::new`, in words.
+pub const DEFAULT_RAM_WORDS: usize = 64;
+/// Register count of `Processor::new`.
+pub const DEFAULT_REGISTERS: usize = 4;
+/// The most registers a machine can have. Register fields are two bits wide, so an
+/// instruction can't name more than R0-R3.
+pub const MAX_REGISTERS: usize = 4;
+
+/// Cycle limit of `Processor::new`, far more than any of the examples needs but
+/// small enough that a runaway loop stops within a second or so.
+pub const DEFAULT_CYCLE_LIMIT: u64 = 10_000_000;
+
+/// When a conditional jump clears the flag register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlagClearPolicy {
+    /// Clear only when the jump is taken, so a not-taken jump leaves the
+    /// comparison available for the next branch.
+    #[default]
+    WhenTaken,
+    /// Clear after every conditional jump, taken or not.
+    Always,
+    /// Never clear; flags change only when another instruction sets them.
+    Never,
+}
+
+/// An in-memory sink whose contents can still be read after it is attached to a processor.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A snapshot of the architectural state: everything a program can observe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessorState {
+    pub registers: Vec<u32>,
+    pub program_counter: usize,
+    pub ram: Vec<u32>,
+    pub flag_register: u32,
+    pub halt: bool
+}
+
+/// Why `Processor::restore` turned a state down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    /// The state has this many RAM words and registers, which the machine doesn't.
+    SizeMismatch { ram_words: usize, registers: usize },
+    /// The program counter points past the end of RAM.
+    ProgramCounterOutOfRange(usize)
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestoreError::SizeMismatch { ram_words, registers } => {
+                write!(f, "the state is for {} words of RAM and {} registers", ram_words, registers)
+            },
+            RestoreError::ProgramCounterOutOfRange(address) => write!(f, "pc {} is past the end of RAM", address)
+        }
+    }
+}
+
+/// How much trace output the processor prints. Whatever the program writes to the
+/// output port is printed at every level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Nothing but the program's own output, for embedding.
+    Quiet,
+    /// The program counter, disassembly and effect of each instruction, and the
+    /// final register state after a run.
+    #[default]
+    Normal,
+    /// Everything in `Normal`, plus a plain-English explanation of each instruction.
+    Explain,
+    /// Everything in `Explain`, plus the raw opcode and operand bits.
+    Verbose,
+}
+
+/// How fast `run` goes from one cycle to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Clock {
+    /// Each cycle starts as soon as the last one finishes.
+    #[default]
+    Immediate,
+    /// A pause this long after every cycle, to watch a program as it goes.
+    Delayed(Duration),
+}
+
+/// Loading from this address reads the next value from the input queue instead of
+/// RAM, trapping with `Trap::NoInput` if it is empty.
+pub const INPUT_PORT: usize = 60;
+/// Loading from this address reads the next number from the random generator, see
+/// `Processor::set_random_seed`.
+pub const RANDOM_PORT: usize = 61;
+/// The random seed of a new processor.
+pub const DEFAULT_RANDOM_SEED: u32 = 0x2545F491;
+
+/// Storing to this address writes the value to the output port instead of RAM.
+pub const OUTPUT_PORT: usize = 62;
+/// Storing to this address selects the output port format (see `OutputFormat::from_control`).
+pub const OUTPUT_CONTROL_PORT: usize = 63;
+
+/// How values written to the output port are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Decimal,
+    SignedDecimal,
+    Hex,
+    Ascii,
+}
+
+/// Whether values written to the output port come out as text or as raw bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PortEncoding {
+    /// Rendered as text in the current `OutputFormat`.
+    #[default]
+    Text,
+    /// Only the low byte, raw.
+    Byte,
+    /// All four bytes, raw, in the given byte order.
+    Word(Endianness),
+}
+
+impl OutputFormat {
+    /// Maps a value stored to the control port onto a format.
+    pub fn from_control(value: u32) -> Option<OutputFormat> {
+        match value {
+            0 => Some(OutputFormat::Decimal),
+            1 => Some(OutputFormat::SignedDecimal),
+            2 => Some(OutputFormat::Hex),
+            3 => Some(OutputFormat::Ascii),
+            _ => None
+        }
+    }
+
+    pub fn render(self, value: u32) -> String {
+        match self {
+            OutputFormat::Decimal => value.to_string(),
+            OutputFormat::SignedDecimal => (value as i32).to_string(),
+            OutputFormat::Hex => format!("{:#x}", value),
+            OutputFormat::Ascii => ((value as u8) as char).to_string(),
+        }
+    }
+}
+
+/// Why the processor stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// A `HALT` instruction was executed.
+    Halted,
+    /// The last RAM cell was executed; there is nothing left to fetch.
+    EndOfMemory,
+    /// The requested number of steps ran without the machine stopping.
+    StepLimit,
+    /// Execution was stopped by a trap; the machine can be resumed once it is cleared.
+    Trap(Trap),
+    /// A `dbg` instruction at this address paused execution. Running again resumes
+    /// after it.
+    DebugTrap(usize),
+}
+
+/// The outcome of one `Processor::step`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// The instruction ran and the machine can keep going.
+    Running,
+    /// The machine stopped on this step and won't run until the reason is dealt with.
+    Stopped(HaltReason),
+}
+
+impl StepResult {
+    pub fn is_stopped(self) -> bool {
+        matches!(self, StepResult::Stopped(_))
+    }
+}
+
+/// Conditions that stop execution without the program halting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// A load from `INPUT_PORT` found the input queue empty. Pushing more input
+    /// resumes it.
+    NoInput,
+    /// The fuel budget set with `Processor::set_fuel` ran out.
+    OutOfFuel,
+    /// This many instructions ran in total, the limit set with
+    /// `Processor::set_cycle_limit`. Usually a loop with no way out.
+    CycleLimit(u64),
+    /// An instruction named a register the register file doesn't have.
+    RegisterOutOfRange(u32),
+    /// A jump pointed outside RAM.
+    JumpOutOfRange(i64),
+    /// A load or store named an address past the end of RAM.
+    AddressOutOfRange(u32),
+    /// Loop detection saw the machine return to an earlier state at this address.
+    NoProgress(usize),
+    /// The wall-clock deadline set with `Processor::set_deadline` passed.
+    Timeout,
+}
+
+// ________      000000      000000000000000000
+//                  6                18
+//   EXTRA       OPCODE            DATA
+//
+// Opcodes 0-15 fit in the original 4-bit field; opcodes from 16 up use the
+// two extra bits above it.
+
+/// Mask selecting the 18 DATA bits of an instruction.
+pub const OPERAND_MASK: u32 = (1 << 18) - 1;
+
+pub fn print_as_assembly(instruction: u32) {
+    println!("{}", disassemble(instruction));
+}
+
+pub fn get_opcode_name(opcode: u32) -> &'static str {
+    match opcode {
+        0 => "nop",
+        1 => "ldi",
+        2 => "add",
+        3 => "sub",
+        4 => "cmp",
+        5 => "jmp",
+        6 => "jeq",
+        7 => "jgt",
+        8 => "jlt",
+        9 => "sto",
+        10 => "lod",
+        11 => "and",
+        12 => "or",
+        13 => "xor",
+        14 => "shl",
+        15 => "hlt",
+        16 => "neg",
+        17 => "bit",
+        18 => "jge",
+        19 => "jle",
+        20 => "jne",
+        21 => "rdpc",
+        22 => "jr",
+        23 => "lea",
+        24 => "cmoveq",
+        25 => "cmovgt",
+        26 => "cmovlt",
+        27 => "dbg",
+        28 => "jcs",
+        29 => "shr",
+        30 => "not",
+        31 => "ldx",
+        32 => "stx",
+        33 => "mul",
+        _ => "???"
+    }
+}
+
+pub fn get_opcode_name_long(opcode: u32) -> &'static str {
+    match opcode {
+        0 => "NO-OP",
+        1 => "LOAD_IMMED",
+        2 => "ADD",
+        3 => "SUB",
+        4 => "CMP_IMMED",
+        5 => "JMP",
+        6 => "JMP_EQ",
+        7 => "JMP_GT",
+        8 => "JMP_LT",
+        9 => "STORE",
+        10 => "LOAD",
+        11 => "AND",
+        12 => "OR",
+        13 => "XOR",
+        14 => "SHIFT_LEFT",
+        15 => "HALT",
+        16 => "NEG",
+        17 => "BIT_TEST",
+        18 => "JMP_GE",
+        19 => "JMP_LE",
+        20 => "JMP_NE",
+        21 => "READ_PC",
+        22 => "JMP_REL",
+        23 => "LOAD_ADDR",
+        24 => "MOV_EQ",
+        25 => "MOV_GT",
+        26 => "MOV_LT",
+        27 => "DEBUG_TRAP",
+        28 => "JMP_CS",
+        29 => "SHIFT_RIGHT",
+        30 => "NOT",
+        31 => "LOAD_INDEXED",
+        32 => "STORE_INDEXED",
+        33 => "MULTIPLY",
+        _ => "UNKNOWN"
+    }
+}
+
+pub fn disassemble(instruction: u32) -> String {
+    let opcode = instruction >> 18;
+    let operand = instruction & OPERAND_MASK;
+
+    let mut final_string = String::from(get_opcode_name_long(opcode));
+
+    match opcode {
+        1 | 4 => { 
+            let immediate_value = operand >> 2;
+            let target_register = operand & 0b11;
+
+            final_string.push(' ');
+            final_string.push_str(&u32::to_string(&immediate_value));
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&target_register));
+        },
+        2 | 3 | 11..=14 | 29 | 33 => {
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&(operand >> 4)));
+            final_string.push_str(", R");
+            final_string.push_str(&u32::to_string(&((operand & 0b001100) >> 2)));
+            final_string.push_str(" -> R");
+            final_string.push_str(&u32::to_string(&(operand & 0b000011)));
+        },
+        5..=8 | 18..=20 | 28 => {
+            final_string.push(' ');
+            final_string.push_str(&u32::to_string(&(operand & 0b11111)));
+        },
+        9 => {
+            final_string.push(' ');
+            final_string.push_str(&u32::to_string(&((operand >> 2) & 0b111111)));
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&(operand & 0b11)));
+        },
+        16 | 21 | 30 => {
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&(operand & 0b11)));
+        },
+        17 => {
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&(operand & 0b11)));
+            final_string.push(' ');
+            final_string.push_str(&u32::to_string(&((operand >> 2) & 0b11111)));
+        },
+        22 => {
+            final_string.push(' ');
+            final_string.push_str(&i32::to_string(&jump_offset(operand)));
+        },
+        10 | 23 => {
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&(operand & 0b11)));
+            final_string.push(' ');
+            final_string.push_str(&u32::to_string(&((operand >> 2) & 0b111111)));
+        },
+        24..=26 | 31 => {
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&(operand & 0b11)));
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&((operand >> 2) & 0b11)));
+        },
+        32 => {
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&((operand >> 2) & 0b11)));
+            final_string.push_str(" R");
+            final_string.push_str(&u32::to_string(&(operand & 0b11)));
+        },
+        _ => {}
+    }
+
+    final_string
+}
+
+// Flag register bits. A comparison sets exactly one of the comparison bits;
+// conditional jumps clear them according to the processor's `FlagClearPolicy`.
+// The carry bit is separate: only `add` and `sub` change it.
+
+/// The compared values were equal (the result was zero).
+pub const FLAG_ZERO: u32 = 0b001;
+/// The first value was less than the second (the result was negative).
+pub const FLAG_SIGN: u32 = 0b010;
+/// The first value was greater than the second (the result was positive).
+pub const FLAG_GREATER: u32 = 0b100;
+/// The last `add` carried out of bit 31, or the last `sub` borrowed (its result
+/// wrapped around).
+pub const FLAG_CARRY: u32 = 0b1000;
+/// The bits a comparison sets.
+pub const COMPARISON_FLAGS: u32 = FLAG_ZERO | FLAG_SIGN | FLAG_GREATER;
+
+pub fn comparison_flags(ordering: Ordering) -> u32 {
+    match ordering {
+        Ordering::Less => FLAG_SIGN,
+        Ordering::Equal => FLAG_ZERO,
+        Ordering::Greater => FLAG_GREATER
+    }
+}
+
+/// Whether the condition of the conditional jump or move `opcode` holds with the
+/// given flags.
+pub fn condition_holds(opcode: u32, flag_register: u32) -> bool {
+    match opcode {
+        6 | 24 => flag_register & FLAG_ZERO != 0,
+        7 | 25 => flag_register & FLAG_GREATER != 0,
+        8 | 26 => flag_register & FLAG_SIGN != 0,
+        18 => flag_register & (FLAG_GREATER | FLAG_ZERO) != 0,
+        19 => flag_register & (FLAG_SIGN | FLAG_ZERO) != 0,
+        20 => flag_register & (FLAG_GREATER | FLAG_SIGN) != 0,
+        28 => flag_register & FLAG_CARRY != 0,
+        _ => false
+    }
+}
+
+pub fn condition_name(opcode: u32) -> &'static str {
+    match opcode {
+        6 | 24 => "EQ",
+        7 | 25 => "GT",
+        8 | 26 => "LT",
+        18 => "GE",
+        19 => "LE",
+        20 => "NE",
+        28 => "CS",
+        _ => "?"
+    }
+}
+
+/// Sign-extends the 5-bit offset field of `jr`.
+pub fn jump_offset(operand: u32) -> i32 {
+    ((operand as i32) << 27) >> 27
+}
+
+/// The flag register decoded into the condition it records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagState {
+    Clear,
+    Equal,
+    Greater,
+    Less
+}
+
+impl FlagState {
+    pub fn from_register(flag_register: u32) -> FlagState {
+        if flag_register & FLAG_ZERO != 0 {
+            FlagState::Equal
+        }
+        else if flag_register & FLAG_GREATER != 0 {
+            FlagState::Greater
+        }
+        else if flag_register & FLAG_SIGN != 0 {
+            FlagState::Less
+        }
+        else {
+            FlagState::Clear
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FlagState::Clear => "clear",
+            FlagState::Equal => "EQ",
+            FlagState::Greater => "GT",
+            FlagState::Less => "LT"
+        }
+    }
+}
+
+/// Describes the flag register value in words.
+pub fn describe_flag(flag_register: u32) -> &'static str {
+    FlagState::from_register(flag_register).name()
+}
+
+/// One change to the flag register, recorded when the flag trace is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagChange {
+    /// Instructions executed before this one.
+    pub cycle: u64,
+    pub program_counter: usize,
+    pub instruction: u32,
+    pub old: FlagState,
+    pub new: FlagState
+}
+
+impl fmt::Display for FlagChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "cycle {} [{}] {}: {} -> {}",
+            self.cycle,
+            self.program_counter,
+            disassemble(self.instruction),
+            self.old.name(),
+            self.new.name()
+        )
+    }
+}
+
+/// One executed instruction, recorded when the execution trace is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub program_counter: usize,
+    pub instruction: u32,
+    pub mnemonic: &'static str,
+    /// The register file after the instruction executed.
+    pub registers: Vec<u32>
+}
+
+/// How many instructions a traced run executed, in total and per mnemonic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceSummary {
+    pub cycles: u64,
+    pub counts: BTreeMap<&'static str, u64>
+}
+
+impl TraceSummary {
+    pub fn from_trace(trace: &[TraceRecord]) -> TraceSummary {
+        let mut counts = BTreeMap::new();
+
+        for record in trace {
+            *counts.entry(record.mnemonic).or_insert(0) += 1;
+        }
+
+        TraceSummary { cycles: trace.len() as u64, counts }
+    }
+}
+
+impl fmt::Display for TraceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} cycles", self.cycles)?;
+
+        for (mnemonic, count) in &self.counts {
+            writeln!(f, "{:>8} {}", count, mnemonic)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Chainable configuration for a `Processor`, e.g.
+/// `Processor::builder().verbosity(Verbosity::Quiet).fuel(1000).build()`.
+/// Anything not set keeps the `Processor::new` default.
+#[derive(Default)]
+pub struct ProcessorBuilder {
+    verbosity: Verbosity,
+    output_format: OutputFormat,
+    fuel: Option<u64>,
+    /// The cycle limit, if not the default.
+    cycle_limit: Option<Option<u64>>,
+    flag_clear_policy: FlagClearPolicy,
+    memory_model: MemoryModel,
+    loop_detection: Option<usize>,
+    flag_trace: bool,
+    execution_trace: bool,
+    deadline: Option<Instant>,
+    cycle_delay: Duration,
+    /// RAM words, if not `DEFAULT_RAM_WORDS`.
+    ram_words: Option<usize>,
+    /// Register count, if not `DEFAULT_REGISTERS`.
+    registers: Option<usize>,
+    ram_fill: u32,
+    port_encoding: PortEncoding,
+    sinks: Vec<Box<dyn Write>>
+}
+
+impl ProcessorBuilder {
+    pub fn verbosity(mut self, verbosity: Verbosity) -> ProcessorBuilder {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Shorthand for tracing every cycle (`Verbosity::Normal`) or nothing
+    /// (`Verbosity::Quiet`).
+    pub fn debug(self, debug: bool) -> ProcessorBuilder {
+        self.verbosity(if debug { Verbosity::Normal } else { Verbosity::Quiet })
+    }
+
+    pub fn output_format(mut self, format: OutputFormat) -> ProcessorBuilder {
+        self.output_format = format;
+        self
+    }
+
+    pub fn fuel(mut self, amount: u64) -> ProcessorBuilder {
+        self.fuel = Some(amount);
+        self
+    }
+
+    /// See `Processor::set_cycle_limit`.
+    pub fn cycle_limit(mut self, limit: Option<u64>) -> ProcessorBuilder {
+        self.cycle_limit = Some(limit);
+        self
+    }
+
+    pub fn flag_clear_policy(mut self, policy: FlagClearPolicy) -> ProcessorBuilder {
+        self.flag_clear_policy = policy;
+        self
+    }
+
+    pub fn memory_model(mut self, memory_model: MemoryModel) -> ProcessorBuilder {
+        self.memory_model = memory_model;
+        self
+    }
+
+    pub fn loop_detection(mut self, history: usize) -> ProcessorBuilder {
+        self.loop_detection = Some(history);
+        self
+    }
+
+    pub fn flag_trace(mut self) -> ProcessorBuilder {
+        self.flag_trace = true;
+        self
+    }
+
+    pub fn execution_trace(mut self) -> ProcessorBuilder {
+        self.execution_trace = true;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> ProcessorBuilder {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn clock(mut self, clock: Clock) -> ProcessorBuilder {
+        self.cycle_delay = match clock {
+            Clock::Immediate => Duration::ZERO,
+            Clock::Delayed(delay) => delay
+        };
+        self
+    }
+
+    /// See `Processor::with_sizes`.
+    pub fn ram(mut self, words: usize) -> ProcessorBuilder {
+        self.ram_words = Some(words);
+        self
+    }
+
+    /// See `Processor::with_sizes`.
+    pub fn registers(mut self, count: usize) -> ProcessorBuilder {
+        self.registers = Some(count);
+        self
+    }
+
+    /// `ram` and `registers` in one.
+    pub fn sizes(self, ram_words: usize, registers: usize) -> ProcessorBuilder {
+        self.ram(ram_words).registers(registers)
+    }
+
+    pub fn ram_fill(mut self, pattern: u32) -> ProcessorBuilder {
+        self.ram_fill = pattern;
+        self
+    }
+
+    pub fn port_encoding(mut self, encoding: PortEncoding) -> ProcessorBuilder {
+        self.port_encoding = encoding;
+        self
+    }
+
+    /// Shorthand for `clock(Clock::Delayed(delay))`.
+    pub fn cycle_delay(self, delay: Duration) -> ProcessorBuilder {
+        self.clock(Clock::Delayed(delay))
+    }
+
+    pub fn sink(mut self, sink: Box<dyn Write>) -> ProcessorBuilder {
+        self.sinks.push(sink);
+        self
+    }
+
+    pub fn build(self) -> Processor {
+        let mut cpu = Processor::with_sizes(
+            self.ram_words.unwrap_or(DEFAULT_RAM_WORDS),
+            self.registers.unwrap_or(DEFAULT_REGISTERS)
+        );
+
+        cpu.verbosity = self.verbosity;
+        cpu.output_format = self.output_format;
+        cpu.fuel = self.fuel;
+        cpu.cycle_limit = self.cycle_limit.unwrap_or(cpu.cycle_limit);
+        cpu.flag_clear_policy = self.flag_clear_policy;
+        cpu.memory_model = self.memory_model;
+        cpu.loop_detector = self.loop_detection.map(LoopDetector::new);
+        cpu.flag_trace = self.flag_trace.then(Vec::new);
+        cpu.execution_trace = self.execution_trace.then(Vec::new);
+        cpu.deadline = self.deadline;
+        cpu.cycle_delay = self.cycle_delay;
+        cpu.fill_ram(self.ram_fill);
+        cpu.port_encoding = self.port_encoding;
+        cpu.sinks = RefCell::new(self.sinks);
+
+        cpu
+    }
+}
+
+impl Default for Processor {
+    fn default() -> Processor {
+        Processor::new()
+    }
+}
+
+impl Processor {
+    pub fn builder() -> ProcessorBuilder {
+        ProcessorBuilder::default()
+    }
+
+    /// A processor with `DEFAULT_RAM_WORDS` of RAM and `DEFAULT_REGISTERS` registers.
+    pub fn new() -> Processor {
+        Processor::with_sizes(DEFAULT_RAM_WORDS, DEFAULT_REGISTERS)
+    }
+
+    /// A processor with `ram_words` of RAM and `registers` registers. Fewer than
+    /// `MAX_REGISTERS` registers is fine; naming one the machine doesn't have traps.
+    ///
+    /// Instruction fields limit how much of a bigger RAM is in reach. `jmp` and the
+    /// conditional jumps can only target 0-31, so code past 31 runs only by falling
+    /// through to it or with `jr`. `lod` and `sto` address 0-63 (the output ports
+    /// stay at 62 and 63); words past that are only reachable through `ldx`/`stx`.
+    ///
+    /// Panics if `ram_words` is 0, since there would be nowhere to fetch from, or if
+    /// `registers` is more than `MAX_REGISTERS`.
+    pub fn with_sizes(ram_words: usize, registers: usize) -> Processor {
+        assert!(ram_words > 0, "RAM needs at least one word.");
+        assert!(registers <= MAX_REGISTERS, "Instructions can only name {} registers.", MAX_REGISTERS);
+
+        Processor {
+            registers: vec![0; registers],
+            program_counter: 0,
+            ram: vec![0; ram_words],
+            flag_register: 0,
+            halt: false,
+            output_format: OutputFormat::default(),
+            verbosity: Verbosity::default(),
+            input: VecDeque::new(),
+            random_state: DEFAULT_RANDOM_SEED,
+            fuel: None,
+            cycle_limit: Some(DEFAULT_CYCLE_LIMIT),
+            sinks: RefCell::new(Vec::new()),
+            flag_clear_policy: FlagClearPolicy::default(),
+            memory_model: MemoryModel::default(),
+            rom: vec![0; ram_words],
+            loop_detector: None,
+            cycles: 0,
+            flag_trace: None,
+            execution_trace: None,
+            deadline: None,
+            cycle_delay: Duration::ZERO,
+            port_encoding: PortEncoding::default(),
+            step_callback: None
+        }
+    }
+
+    /// Sets every RAM cell to `pattern`, e.g. `POISON`, so reads of memory the
+    /// program never wrote stand out instead of quietly reading 0. Call it before
+    /// `load_program`.
+    pub fn fill_ram(&mut self, pattern: u32) {
+        self.ram.fill(pattern);
+    }
+
+    /// Chooses the memory model. Set it before `load_program`.
+    pub fn set_memory_model(&mut self, memory_model: MemoryModel) {
+        self.memory_model = memory_model;
+    }
+
+    pub fn set_flag_clear_policy(&mut self, policy: FlagClearPolicy) {
+        self.flag_clear_policy = policy;
+    }
+
+    /// Attaches another sink. Once any sink is attached, output stops going to stdout
+    /// unless `io::stdout()` is attached as well.
+    pub fn add_sink(&mut self, sink: Box<dyn Write>) {
+        self.sinks.get_mut().push(sink);
+    }
+
+    /// Writes to every attached sink, or to stdout if there are none.
+    fn emit(&self, message: fmt::Arguments) {
+        let mut sinks = self.sinks.borrow_mut();
+
+        if sinks.is_empty() {
+            print!("{}", message);
+            return;
+        }
+
+        for sink in sinks.iter_mut() {
+            // A failing sink shouldn't stop the machine or starve the others.
+            let _ = sink.write_fmt(message);
+        }
+    }
+
+    /// Limits execution to `amount` more instructions. Refuelling after an
+    /// `OutOfFuel` trap resumes where the program stopped.
+    pub fn set_fuel(&mut self, amount: u64) {
+        self.fuel = Some(amount);
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Traps with `Trap::CycleLimit` once `limit` instructions have run in total,
+    /// or never with `None`. Defaults to `DEFAULT_CYCLE_LIMIT`, so a program stuck
+    /// in a loop stops instead of spinning forever.
+    pub fn set_cycle_limit(&mut self, limit: Option<u64>) {
+        self.cycle_limit = limit;
+    }
+
+    pub fn cycle_limit(&self) -> Option<u64> {
+        self.cycle_limit
+    }
+
+    /// Calls `callback` before each instruction executes with the instruction word and
+    /// its bit-fields from `encoding::fields`, e.g. to animate which bits mean what.
+    pub fn set_step_callback(&mut self, callback: StepCallback) {
+        self.step_callback = Some(callback);
+    }
+
+    /// Traps with `Trap::Timeout` once `deadline` has passed. The clock is only
+    /// checked every `DEADLINE_CHECK_INTERVAL` instructions, so the trap can come a
+    /// little late.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Traps with `Trap::NoProgress` when the machine state (program counter,
+    /// registers, flags and RAM) matches one from the last `history` steps.
+    pub fn set_loop_detection(&mut self, history: usize) {
+        self.loop_detector = Some(LoopDetector::new(history));
+    }
+
+    /// Starts recording every change to the flag register, see `flag_trace`.
+    pub fn enable_flag_trace(&mut self) {
+        self.flag_trace.get_or_insert_with(Vec::new);
+    }
+
+    /// The flag changes recorded so far, oldest first.
+    pub fn flag_trace(&self) -> &[FlagChange] {
+        self.flag_trace.as_deref().unwrap_or(&[])
+    }
+
+    /// Starts recording every instruction executed, see `execution_trace`. `run`
+    /// then ends by printing a `TraceSummary`.
+    pub fn enable_execution_trace(&mut self) {
+        self.execution_trace.get_or_insert_with(Vec::new);
+    }
+
+    /// The instructions recorded so far, oldest first.
+    pub fn execution_trace(&self) -> &[TraceRecord] {
+        self.execution_trace.as_deref().unwrap_or(&[])
+    }
+
+    /// Totals for the execution trace, or `None` if it isn't on.
+    pub fn trace_summary(&self) -> Option<TraceSummary> {
+        self.execution_trace.as_deref().map(TraceSummary::from_trace)
+    }
+
+    fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+
+        self.program_counter.hash(&mut hasher);
+        self.registers.hash(&mut hasher);
+        self.flag_register.hash(&mut hasher);
+        self.ram.hash(&mut hasher);
+        self.input.len().hash(&mut hasher);
+        self.random_state.hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+        self.verbosity = verbosity;
+    }
+
+    /// Makes `run` pause for `delay` after every cycle. `step` never pauses.
+    pub fn set_cycle_delay(&mut self, delay: Duration) {
+        self.cycle_delay = delay;
+    }
+
+    /// Whether the current verbosity includes trace lines of `level`.
+    fn traces(&self, level: Verbosity) -> bool {
+        self.verbosity >= level
+    }
+
+    /// Prints a trace line if the current verbosity includes `level`.
+    fn trace(&self, level: Verbosity, message: fmt::Arguments) {
+        if self.traces(level) {
+            self.emit(format_args!("{}\n", message));
+        }
+    }
+
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.output_format = format;
+    }
+
+    /// Queues `value` to be read from `INPUT_PORT` after any already waiting.
+    pub fn push_input(&mut self, value: u32) {
+        self.input.push_back(value);
+    }
+
+    pub fn clear_input(&mut self) {
+        self.input.clear();
+    }
+
+    /// The values not yet read from `INPUT_PORT`, oldest first.
+    pub fn pending_input(&self) -> impl Iterator<Item = u32> + '_ {
+        self.input.iter().copied()
+    }
+
+    /// Restarts the numbers read from `RANDOM_PORT`. The same seed always gives the
+    /// same sequence; 0 is replaced by `DEFAULT_RANDOM_SEED`.
+    pub fn set_random_seed(&mut self, seed: u32) {
+        self.random_state = if seed == 0 { DEFAULT_RANDOM_SEED } else { seed };
+    }
+
+    /// The current state of the random generator, which seeds the rest of the sequence.
+    pub fn random_seed(&self) -> u32 {
+        self.random_state
+    }
+
+    /// The next xorshift32 number.
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.random_state;
+
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+
+        self.random_state = x;
+
+        x
+    }
+
+    /// Reads `address`, which may be one of the input ports.
+    fn load(&mut self, address: u32) -> Result<u32, Trap> {
+        match address as usize {
+            INPUT_PORT => self.input.pop_front().ok_or(Trap::NoInput),
+            RANDOM_PORT => Ok(self.next_random()),
+            _ => Ok(*self.ram_cell(address)?)
+        }
+    }
+
+    pub fn set_port_encoding(&mut self, encoding: PortEncoding) {
+        self.port_encoding = encoding;
+    }
+
+    /// Writes raw bytes to every attached sink, or to stdout if there are none.
+    fn emit_bytes(&self, bytes: &[u8]) {
+        let mut sinks = self.sinks.borrow_mut();
+
+        if sinks.is_empty() {
+            let _ = io::stdout().write_all(bytes);
+            return;
+        }
+
+        for sink in sinks.iter_mut() {
+            let _ = sink.write_all(bytes);
+        }
+    }
+
+    fn write_output(&mut self, value: u32) {
+        match self.port_encoding {
+            PortEncoding::Byte => self.emit_bytes(&[value as u8]),
+            PortEncoding::Word(endianness) => self.emit_bytes(&endianness.word_bytes(value)),
+            PortEncoding::Text => {
+                let rendered = self.output_format.render(value);
+
+                // Text is written a character at a time, numbers one per line.
+                if self.output_format == OutputFormat::Ascii {
+                    self.emit(format_args!("{}", rendered));
+                }
+                else {
+                    self.emit(format_args!("{}\n", rendered));
+                }
+            }
+        }
+    }
+    
+    /// Copies `program` to the start of RAM (and ROM in `MemoryModel::Harvard`).
+    ///
+    /// Panics if the program is longer than RAM.
+    pub fn load_program(&mut self, program:&[u32]) {
+        assert!(program.len() <= self.ram.len(), "A {}-word program doesn't fit in {} words of RAM.", program.len(), self.ram.len());
+
+        for (i, &instruction) in program.iter().enumerate() {
+            self.ram[i] = instruction;
+
+            if self.memory_model == MemoryModel::Harvard {
+                self.rom[i] = instruction;
+            }
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halt
+    }
+
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    pub fn registers(&self) -> &[u32] {
+        &self.registers
+    }
+
+    pub fn ram(&self) -> &[u32] {
+        &self.ram
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flag_register
+    }
+
+    fn fetch_instruction(&mut self) -> u32 {
+        match self.memory_model {
+            MemoryModel::Shared => self.ram[self.program_counter],
+            MemoryModel::Harvard => self.rom[self.program_counter]
+        }
+    }
+
+    /// Checks a decoded register field against the size of the register file.
+    fn register_index(&self, field: u32) -> Result<usize, Trap> {
+        if (field as usize) < self.registers.len() {
+            Ok(field as usize)
+        }
+        else {
+            Err(Trap::RegisterOutOfRange(field))
+        }
+    }
+
+    /// Checks a RAM address field against the size of RAM.
+    fn ram_cell(&mut self, address: u32) -> Result<&mut u32, Trap> {
+        self.ram.get_mut(address as usize).ok_or(Trap::AddressOutOfRange(address))
+    }
+
+    /// Writes `value` to `address`, which may be one of the output ports.
+    fn store(&mut self, address: u32, value: u32) -> Result<(), Trap> {
+        match address as usize {
+            OUTPUT_PORT => self.write_output(value),
+            OUTPUT_CONTROL_PORT => {
+                if let Some(format) = OutputFormat::from_control(value) {
+                    self.output_format = format;
+                }
+            }
+            _ => *self.ram_cell(address)? = value
+        }
+
+        Ok(())
+    }
+
+    /// Checks an absolute jump address against the size of RAM.
+    fn jump_target(&self, address: u32) -> Result<usize, Trap> {
+        if (address as usize) < self.ram.len() {
+            Ok(address as usize)
+        }
+        else {
+            Err(Trap::JumpOutOfRange(address as i64))
+        }
+    }
+
+    /// Records a comparison, leaving the carry bit alone.
+    fn set_comparison(&mut self, ordering: Ordering) {
+        self.flag_register = (self.flag_register & !COMPARISON_FLAGS) | comparison_flags(ordering);
+    }
+
+    fn set_carry(&mut self, carry: bool) {
+        self.flag_register = (self.flag_register & !FLAG_CARRY) | if carry { FLAG_CARRY } else { 0 };
+    }
+
+    /// Executes the instruction at the program counter, returning the address to
+    /// continue at if it jumped. A jump target is the address of the next instruction
+    /// to run, the same for every kind of jump. A trap leaves the machine state as it
+    /// was before the instruction.
+    fn execute_instruction(&mut self) -> Result<Option<usize>, Trap> {
+        let instruction = self.fetch_instruction();
+
+        let opcode = instruction >> 18;
+        let operand = instruction & OPERAND_MASK;
+
+        let mut jump = None;
+
+        match opcode {
+            1 => {
+                let immediate_value = operand >> 2;
+                let target_register = self.register_index(operand & 0b11)?;
+                self.registers[target_register] = immediate_value;
+            }
+            2 => {
+                let reg_a = self.register_index(operand >> 4)?;
+                let reg_b = self.register_index((operand & 0b001100) >> 2)?;
+                let reg_c = self.register_index(operand & 0b000011)?;
+
+                let (result, carry) = self.registers[reg_a].overflowing_add(self.registers[reg_b]);
+
+                self.registers[reg_c] = result;
+                self.set_carry(carry);
+            }
+            3 => {
+                let reg_a = self.register_index(operand >> 4)?;
+                let reg_b = self.register_index((operand & 0b001100) >> 2)?;
+                let reg_c = self.register_index(operand & 0b000011)?;
+
+                let (result, borrow) = self.registers[reg_a].overflowing_sub(self.registers[reg_b]);
+
+                self.registers[reg_c] = result;
+                self.set_carry(borrow);
+            }
+            // Ra * Rb -> Rc, keeping the low 32 bits of the product. The flags are
+            // left alone.
+            33 => {
+                let reg_a = self.register_index(operand >> 4)?;
+                let reg_b = self.register_index((operand & 0b001100) >> 2)?;
+                let reg_c = self.register_index(operand & 0b000011)?;
+
+                self.registers[reg_c] = self.registers[reg_a].wrapping_mul(self.registers[reg_b]);
+            }
+            // Bitwise operations use the add/sub layout, Ra op Rb -> Rc, and leave the
+            // flags alone. A shift takes its amount from Rb; shifting by 32 or more
+            // gives 0.
+            11..=14 | 29 => {
+                let reg_a = self.register_index(operand >> 4)?;
+                let reg_b = self.register_index((operand & 0b001100) >> 2)?;
+                let reg_c = self.register_index(operand & 0b000011)?;
+
+                let (a, b) = (self.registers[reg_a], self.registers[reg_b]);
+
+                self.registers[reg_c] = match opcode {
+                    11 => a & b,
+                    12 => a | b,
+                    13 => a ^ b,
+                    14 => a.checked_shl(b).unwrap_or(0),
+                    _ => a.checked_shr(b).unwrap_or(0)
+                };
+            }
+            30 => {
+                let target_register = self.register_index(operand & 0b11)?;
+
+                self.registers[target_register] = !self.registers[target_register];
+            }
+            4 => {
+                let immed_compare = operand >> 2; 
+                let register_addr = self.register_index(operand & (0b11))?;
+
+                // The register is compared with the immediate, so `cmp 5 r0` followed by
+                // `jgt` jumps when R0 > 5.
+                self.set_comparison(self.registers[register_addr].cmp(&immed_compare));
+            }
+            5 => {
+                jump = Some(self.jump_target(operand & (0b11111))?);
+            }
+            22 => {
+                let target = self.program_counter as i64 + jump_offset(operand) as i64;
+
+                if target < 0 || target >= self.ram.len() as i64 {
+                    return Err(Trap::JumpOutOfRange(target));
+                }
+
+                jump = Some(target as usize);
+            }
+            6..=8 | 18..=20 | 28 => {
+                let taken = condition_holds(opcode, self.flag_register);
+
+                if taken {
+                    jump = Some(self.jump_target(operand & (0b11111))?);
+                }
+
+                match self.flag_clear_policy {
+                    FlagClearPolicy::WhenTaken if taken => self.flag_register &= !COMPARISON_FLAGS,
+                    FlagClearPolicy::Always => self.flag_register &= !COMPARISON_FLAGS,
+                    _ => {}
+                }
+            }
+            9 => {
+                let ram_addr = (operand >> 2) & 0b111111;
+                let source_register = self.register_index(operand & 0b11)?;
+
+                self.store(ram_addr, self.registers[source_register])?;
+            }
+            10 => {
+                let ram_addr = (operand >> 2) & 0b111111;
+                let target_register = self.register_index(operand & 0b11)?;
+
+                self.registers[target_register] = self.load(ram_addr)?;
+            }
+            15 => {
+                self.halt = true;
+            }
+            16 => {
+                let target_register = self.register_index(operand & 0b11)?;
+                let result = self.registers[target_register].wrapping_neg();
+
+                self.registers[target_register] = result;
+
+                // Flags are set as if the signed result were compared with zero.
+                self.set_comparison((result as i32).cmp(&0));
+            }
+            17 => {
+                let bit_index = (operand >> 2) & 0b11111;
+                let register_addr = self.register_index(operand & 0b11)?;
+
+                // A clear bit sets ZERO, a set bit sets GREATER (the masked value is positive).
+                let masked = self.registers[register_addr] & (1 << bit_index);
+
+                self.set_comparison(masked.cmp(&0));
+            }
+            21 => {
+                let target_register = self.register_index(operand & 0b11)?;
+
+                // The address of the `rdpc` instruction itself, not the one after it.
+                self.registers[target_register] = self.program_counter as u32;
+            }
+            23 => {
+                let ram_addr = (operand >> 2) & 0b111111;
+                let target_register = self.register_index(operand & 0b11)?;
+
+                // The address itself; RAM isn't read.
+                self.registers[target_register] = ram_addr;
+            }
+            24..=26 => {
+                let target_register = self.register_index(operand & 0b11)?;
+                let source_register = self.register_index((operand >> 2) & 0b11)?;
+
+                // Unlike a conditional jump, a move leaves the flags alone.
+                if condition_holds(opcode, self.flag_register) {
+                    self.registers[target_register] = self.registers[source_register];
+                }
+            }
+            // Indexed `ldx Rn Ra` and `stx Ra Rn` take the address from Ra, so with `lea`
+            // they can walk an array. Ra holds a whole word, reaching all of RAM.
+            31 => {
+                let target_register = self.register_index(operand & 0b11)?;
+                let address_register = self.register_index((operand >> 2) & 0b11)?;
+
+                self.registers[target_register] = self.load(self.registers[address_register])?;
+            }
+            32 => {
+                let source_register = self.register_index(operand & 0b11)?;
+                let address_register = self.register_index((operand >> 2) & 0b11)?;
+
+                self.store(self.registers[address_register], self.registers[source_register])?;
+            }
+            _ => {}
+        }
+
+        Ok(jump)
+    }
+
+    /// The trace line for what the instruction in `before` just did, such as
+    /// `REG[1] <- 5`, or `None` if it changed nothing worth showing.
+    fn describe_effect(&self, before: &StepSnapshot) -> Option<String> {
+        let opcode = before.instruction >> 18;
+        let operand = before.instruction & OPERAND_MASK;
+        let register = (operand & 0b11) as usize;
+
+        let effect = match opcode {
+            1..=3 | 10..=14 | 16 | 21 | 23 | 29..=31 | 33 => format!("REG[{}] <- {}", register, self.registers[register]),
+            24..=26 if condition_holds(opcode, before.flag_register) => {
+                format!("REG[{}] <- {}", register, self.registers[register])
+            }
+            4 => format!("CMP -> [{}]", describe_flag(self.flag_register)),
+            17 => format!("BIT -> [{}]", describe_flag(self.flag_register)),
+            5 => format!("JMP -> [{}]", operand & 0b11111),
+            22 => format!("JMP -> [{}]", before.program_counter as i64 + jump_offset(operand) as i64),
+            9 => format!("RAM[{}] <- {}", (operand >> 2) & 0b111111, before.registers[register]),
+            32 => format!("RAM[{}] <- {}", before.registers[((operand >> 2) & 0b11) as usize], before.registers[register]),
+            _ => return None
+        };
+
+        Some(effect)
+    }
+
+    /// Builds a plain-English sentence describing what the instruction in `before`
+    /// just did.
+    fn explain(&self, before: &StepSnapshot) -> String {
+        let instruction = before.instruction;
+        let registers_before = &before.registers;
+        let opcode = instruction >> 18;
+        let operand = instruction & OPERAND_MASK;
+
+        let sentence = match opcode {
+            0 => "Did nothing.".to_string(),
+            1 => format!("Loaded {} into R{}.", operand >> 2, operand & 0b11),
+            2 | 3 => {
+                let reg_a = (operand >> 4) as usize;
+                let reg_b = ((operand & 0b001100) >> 2) as usize;
+                let reg_c = (operand & 0b000011) as usize;
+
+                let sentence = if opcode == 2 {
+                    format!("Added R{} ({}) and R{} ({}), stored {} in R{}.",
+                        reg_a, registers_before[reg_a], reg_b, registers_before[reg_b], self.registers[reg_c], reg_c)
+                }
+                else {
+                    format!("Subtracted R{} ({}) from R{} ({}), stored {} in R{}.",
+                        reg_b, registers_before[reg_b], reg_a, registers_before[reg_a], self.registers[reg_c], reg_c)
+                };
+
+                if self.flag_register & FLAG_CARRY != 0 {
+                    format!("{} The result wrapped around, so the carry flag is set.", sentence)
+                }
+                else {
+                    sentence
+                }
+            }
+            33 => {
+                let reg_a = (operand >> 4) as usize;
+                let reg_b = ((operand & 0b001100) >> 2) as usize;
+                let reg_c = (operand & 0b000011) as usize;
+
+                format!("Multiplied R{} ({}) by R{} ({}), stored {} in R{}.",
+                    reg_a, registers_before[reg_a], reg_b, registers_before[reg_b], self.registers[reg_c], reg_c)
+            }
+            4 => {
+                let register_addr = (operand & 0b11) as usize;
+
+                format!("Compared R{} ({}) with {}, the flag is now {}.",
+                    register_addr, registers_before[register_addr], operand >> 2, describe_flag(self.flag_register))
+            }
+            5 => format!("Jumped to {}.", operand & 0b11111),
+            22 => {
+                let target = before.program_counter as i64 + jump_offset(operand) as i64;
+
+                format!("Jumped by {} to {}.", jump_offset(operand), target)
+            }
+            6..=8 | 18..=20 | 28 => {
+                let condition = condition_name(opcode);
+
+                if condition_holds(opcode, before.flag_register) {
+                    format!("The condition {} held, so jumped to {}.", condition, operand & 0b11111)
+                }
+                else {
+                    format!("The condition {} did not hold, so did not jump.", condition)
+                }
+            }
+            9 => {
+                let source_register = (operand & 0b11) as usize;
+
+                format!("Stored R{} ({}) into RAM[{}].", source_register, registers_before[source_register], (operand >> 2) & 0b111111)
+            }
+            10 => {
+                let target_register = (operand & 0b11) as usize;
+                let source = match ((operand >> 2) & 0b111111) as usize {
+                    INPUT_PORT => "the input port".to_string(),
+                    RANDOM_PORT => "the random port".to_string(),
+                    address => format!("RAM[{}]", address)
+                };
+
+                format!("Loaded {} ({}) into R{}.", source, self.registers[target_register], target_register)
+            }
+            11..=14 | 29 => {
+                let reg_a = (operand >> 4) as usize;
+                let reg_b = ((operand & 0b001100) >> 2) as usize;
+                let reg_c = (operand & 0b000011) as usize;
+
+                let operation = match opcode {
+                    11 => "ANDed",
+                    12 => "ORed",
+                    13 => "XORed",
+                    14 => "Shifted left",
+                    _ => "Shifted right"
+                };
+                let connective = if matches!(opcode, 14 | 29) { "by" } else { "with" };
+
+                format!("{} R{} ({}) {} R{} ({}), stored {} in R{}.",
+                    operation, reg_a, registers_before[reg_a], connective, reg_b, registers_before[reg_b], self.registers[reg_c], reg_c)
+            }
+            30 => {
+                let target_register = (operand & 0b11) as usize;
+
+                format!("Inverted the bits of R{} ({}), stored {} in R{}.",
+                    target_register, registers_before[target_register], self.registers[target_register], target_register)
+            }
+            15 => "Halted the processor.".to_string(),
+            16 => {
+                let target_register = (operand & 0b11) as usize;
+
+                format!("Negated R{} ({}), stored {} in R{}.",
+                    target_register, registers_before[target_register], self.registers[target_register], target_register)
+            }
+            17 => {
+                let register_addr = (operand & 0b11) as usize;
+                let bit_index = (operand >> 2) & 0b11111;
+                let state = if self.flag_register & FLAG_ZERO != 0 { "clear" } else { "set" };
+
+                format!("Tested bit {} of R{} ({}), it was {}, the flag is now {}.",
+                    bit_index, register_addr, registers_before[register_addr], state, describe_flag(self.flag_register))
+            }
+            21 => {
+                let target_register = (operand & 0b11) as usize;
+
+                format!("Loaded the address of this instruction ({}) into R{}.", self.registers[target_register], target_register)
+            }
+            23 => {
+                let target_register = (operand & 0b11) as usize;
+
+                format!("Loaded the address {} (not its contents) into R{}.", (operand >> 2) & 0b111111, target_register)
+            }
+            24..=26 => {
+                let target_register = (operand & 0b11) as usize;
+                let source_register = ((operand >> 2) & 0b11) as usize;
+                let condition = condition_name(opcode);
+
+                if condition_holds(opcode, self.flag_register) {
+                    format!("The condition {} held, so copied R{} ({}) into R{}.",
+                        condition, source_register, registers_before[source_register], target_register)
+                }
+                else {
+                    format!("The condition {} did not hold, so R{} was left as {}.",
+                        condition, target_register, registers_before[target_register])
+                }
+            }
+            27 => "Paused for the debugger.".to_string(),
+            31 => {
+                let target_register = (operand & 0b11) as usize;
+                let address_register = ((operand >> 2) & 0b11) as usize;
+
+                format!("Loaded RAM[R{}] (RAM[{}], {}) into R{}.",
+                    address_register, registers_before[address_register], self.registers[target_register], target_register)
+            }
+            32 => {
+                let source_register = (operand & 0b11) as usize;
+                let address_register = ((operand >> 2) & 0b11) as usize;
+
+                format!("Stored R{} ({}) into RAM[R{}] (RAM[{}]).",
+                    source_register, registers_before[source_register], address_register, registers_before[address_register])
+            }
+            _ => "Unknown instruction, did nothing.".to_string()
+        };
+
+        format!("{}: {}", get_opcode_name_long(opcode), sentence)
+    }
+
+    /// Executes one instruction and advances the program counter. Nothing is
+    /// printed whatever the verbosity; the only output is what the program itself
+    /// writes to the output port. `run` adds the trace on top.
+    pub fn step(&mut self) -> StepResult {
+        match self.step_inner() {
+            Some(reason) => StepResult::Stopped(reason),
+            None => StepResult::Running
+        }
+    }
+
+    /// `step`, with `Some` for the reason if the machine stopped.
+    fn step_inner(&mut self) -> Option<HaltReason> {
+        if self.halt {
+            return Some(HaltReason::Halted);
+        }
+
+        if self.cycles.is_multiple_of(DEADLINE_CHECK_INTERVAL) && self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some(HaltReason::Trap(Trap::Timeout));
+        }
+
+        if let Some(limit) = self.cycle_limit.filter(|&limit| self.cycles >= limit) {
+            return Some(HaltReason::Trap(Trap::CycleLimit(limit)));
+        }
+
+        if self.fuel == Some(0) {
+            return Some(HaltReason::Trap(Trap::OutOfFuel));
+        }
+
+        // A state only joins the history once its instruction has executed, so a step
+        // that stops early leaves nothing behind to trip the detector on resuming.
+        let state = self.loop_detector.is_some().then(|| self.state_hash());
+
+        if let (Some(detector), Some(state)) = (self.loop_detector.as_ref(), state) {
+            if detector.seen(state) {
+                return Some(HaltReason::Trap(Trap::NoProgress(self.program_counter)));
+            }
+        }
+
+        let flags_before = self.flag_register;
+        let program_counter_before = self.program_counter;
+        let instruction = self.fetch_instruction();
+
+        if let Some(callback) = self.step_callback.as_mut() {
+            callback(instruction, &encoding::fields(instruction));
+        }
+
+        let jump = match self.execute_instruction() {
+            Ok(jump) => jump,
+            Err(trap) => return Some(HaltReason::Trap(trap))
+        };
+
+        if let (Some(detector), Some(state)) = (self.loop_detector.as_mut(), state) {
+            detector.record(state);
+        }
+
+        // The trace follows the comparison state; a change to the carry bit alone isn't recorded.
+        if FlagState::from_register(self.flag_register) != FlagState::from_register(flags_before) {
+            if let Some(trace) = self.flag_trace.as_mut() {
+                trace.push(FlagChange {
+                    cycle: self.cycles,
+                    program_counter: program_counter_before,
+                    instruction,
+                    old: FlagState::from_register(flags_before),
+                    new: FlagState::from_register(self.flag_register)
+                });
+            }
+        }
+
+        if let Some(trace) = self.execution_trace.as_mut() {
+            trace.push(TraceRecord {
+                program_counter: program_counter_before,
+                instruction,
+                mnemonic: get_opcode_name(instruction >> 18),
+                registers: self.registers.clone()
+            });
+        }
+
+        self.cycles += 1;
+
+        // Fuel pays for executed instructions only, so a trap leaves it untouched.
+        if let Some(fuel) = self.fuel.as_mut() {
+            *fuel -= 1;
+        }
+
+        if self.halt {
+            return Some(HaltReason::Halted);
+        }
+
+        match jump {
+            Some(target) => self.program_counter = target,
+            None if self.program_counter == self.ram.len() - 1 => return Some(HaltReason::EndOfMemory),
+            None => self.program_counter += 1
+        }
+
+        if instruction >> 18 == 27 {
+            return Some(HaltReason::DebugTrap(program_counter_before));
+        }
+
+        None
+    }
+
+    pub fn state(&self) -> ProcessorState {
+        ProcessorState {
+            registers: self.registers.clone(),
+            program_counter: self.program_counter,
+            ram: self.ram.clone(),
+            flag_register: self.flag_register,
+            halt: self.halt
+        }
+    }
+
+    /// Puts the machine into `state`, as taken by `state` or found in a state log.
+    /// A state from a machine of another size, or one whose program counter is past
+    /// the end of RAM, is turned down and the machine left as it was.
+    pub fn restore(&mut self, state: &ProcessorState) -> Result<(), RestoreError> {
+        if state.ram.len() != self.ram.len() || state.registers.len() != self.registers.len() {
+            return Err(RestoreError::SizeMismatch { ram_words: state.ram.len(), registers: state.registers.len() });
+        }
+
+        if state.program_counter >= self.ram.len() {
+            return Err(RestoreError::ProgramCounterOutOfRange(state.program_counter));
+        }
+
+        self.registers.clone_from(&state.registers);
+        self.program_counter = state.program_counter;
+        self.ram.clone_from(&state.ram);
+        self.flag_register = state.flag_register;
+        self.halt = state.halt;
+
+        Ok(())
+    }
+
+    /// Steps up to `n` times, stopping early if the machine halts or traps.
+    pub fn step_n(&mut self, n: usize) -> HaltReason {
+        for _ in 0..n {
+            if let StepResult::Stopped(reason) = self.step() {
+                return reason;
+            }
+        }
+
+        HaltReason::StepLimit
+    }
+
+    /// Runs until the machine stops, tracing each cycle according to the verbosity,
+    /// and printing the trace summary at the end if the execution trace is on.
+    pub fn run(&mut self) -> HaltReason {
+        loop {
+            self.trace(Verbosity::Normal, format_args!("[{}]", self.program_counter));
+
+            if let StepResult::Stopped(reason) = self.step_traced() {
+                self.trace(Verbosity::Normal, format_args!("Registers: {:?}", self.registers));
+
+                if let Some(summary) = self.trace_summary() {
+                    self.emit(format_args!("{}", summary));
+                }
+
+                return reason;
+            }
+
+            self.trace(Verbosity::Normal, format_args!(""));
+
+            if !self.cycle_delay.is_zero() {
+                thread::sleep(self.cycle_delay);
+            }
+        }
+    }
+
+    /// `step`, tracing the instruction and its effect according to the verbosity.
+    fn step_traced(&mut self) -> StepResult {
+        if self.verbosity == Verbosity::Quiet || self.halt {
+            return self.step();
+        }
+
+        let before = StepSnapshot {
+            instruction: self.fetch_instruction(),
+            registers: self.registers.clone(),
+            program_counter: self.program_counter,
+            flag_register: self.flag_register
+        };
+        let opcode = before.instruction >> 18;
+        let operand = before.instruction & OPERAND_MASK;
+        let cycles = self.cycles;
+
+        self.trace(Verbosity::Normal, format_args!("{}", disassemble(before.instruction)));
+        self.trace(Verbosity::Verbose, format_args!("\nOPCODE: {:b}\nOPERAND: {:b}", opcode, operand));
+
+        let result = self.step();
+
+        // Only an instruction that completed counts a cycle; a trap changes nothing.
+        if self.cycles != cycles {
+            if let Some(effect) = self.describe_effect(&before) {
+                self.trace(Verbosity::Normal, format_args!("{}", effect));
+            }
+
+            if self.verbosity >= Verbosity::Explain {
+                self.trace(Verbosity::Explain, format_args!("{}", self.explain(&before)));
+            }
+        }
+
+        result
+    }
+
+    /// The RAM cell `instruction` writes, if it is a store to RAM rather than to an
+    /// output port.
+    fn stored_address(&self, instruction: u32) -> Option<usize> {
+        let address = match instruction >> 18 {
+            9 => ((instruction >> 2) & 0b111111) as usize,
+            32 => *self.registers.get(((instruction >> 2) & 0b11) as usize)? as usize,
+            _ => return None
+        };
+
+        (address != OUTPUT_PORT && address != OUTPUT_CONTROL_PORT && address < self.ram.len()).then_some(address)
+    }
+
+    /// Runs, recording the full state after every cycle, until the machine
+    /// stops or `max_len` states have been logged (which stops with
+    /// `HaltReason::StepLimit`). Only what changed each cycle is kept while running;
+    /// the full states are rebuilt at the end. A step that stops the machine without
+    /// executing anything adds no state, so the log has one entry per cycle.
+    pub fn run_with_state_log(&mut self, max_len: usize) -> (HaltReason, Vec<ProcessorState>) {
+        let initial = self.state();
+        let mut deltas: Vec<StateDelta> = Vec::new();
+
+        let reason = loop {
+            if deltas.len() == max_len {
+                break HaltReason::StepLimit;
+            }
+
+            let instruction = if self.halt { 0 } else { self.fetch_instruction() };
+            let cycles = self.cycles;
+            let reason = self.step_inner();
+
+            if self.cycles != cycles {
+                deltas.push(StateDelta {
+                    registers: self.registers.clone(),
+                    program_counter: self.program_counter,
+                    flag_register: self.flag_register,
+                    halt: self.halt,
+                    ram: self.stored_address(instruction).map(|address| (address, self.ram[address]))
+                });
+            }
+
+            if let Some(reason) = reason {
+                break reason;
+            }
+        };
+
+        let mut state = initial;
+        let log = deltas
+            .into_iter()
+            .map(|delta| {
+                state.registers = delta.registers;
+                state.program_counter = delta.program_counter;
+                state.flag_register = delta.flag_register;
+                state.halt = delta.halt;
+
+                if let Some((address, value)) = delta.ram {
+                    state.ram[address] = value;
+                }
+
+                state.clone()
+            })
+            .collect();
+
+        (reason, log)
+    }
+
+    /// Runs until the machine stops, returning every instruction word it
+    /// executed in order, for the timing models.
+    pub fn run_recording(&mut self) -> (HaltReason, Vec<u32>) {
+        let mut stream = Vec::new();
+
+        loop {
+            if !self.halt {
+                stream.push(self.fetch_instruction());
+            }
+
+            if let StepResult::Stopped(reason) = self.step() {
+                return (reason, stream);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quiet() -> Processor {
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+
+        cpu
+    }
+
+    /// Assembles `source` into a quiet processor, ready to run.
+    fn load(source: &str) -> Processor {
+        let mut cpu = quiet();
+
+        cpu.load_program(&assembler::assemble(source).unwrap());
+
+        cpu
+    }
+
+    /// Assembles and runs `source` quietly to completion.
+    fn run(source: &str) -> (Processor, HaltReason) {
+        let mut cpu = load(source);
+        let reason = cpu.run();
+
+        (cpu, reason)
+    }
+
+    /// Runs `source` quietly and returns what it wrote to a `SharedBuffer` sink.
+    fn output(builder: ProcessorBuilder, source: &str) -> String {
+        let buffer = SharedBuffer::default();
+        let mut cpu = builder.verbosity(Verbosity::Quiet).sink(Box::new(buffer.clone())).build();
+
+        cpu.load_program(&assembler::assemble(source).unwrap());
+        cpu.run();
+
+        buffer.contents()
+    }
+
+    #[test]
+    fn step_n_stops_early_when_the_program_halts() {
+        let mut cpu = load("ldi 1 r0\nhlt\nldi 2 r0\n");
+
+        assert_eq!(cpu.step_n(5), HaltReason::Halted);
+        assert_eq!(cpu.registers[0], 1);
+        assert_eq!(cpu.step_n(5), HaltReason::Halted);
+    }
+
+    #[test]
+    fn the_output_port_renders_in_the_configured_format() {
+        assert_eq!(OutputFormat::Ascii.render(65), "A");
+        assert_eq!(OutputFormat::Decimal.render(65), "65");
+        assert_eq!(OutputFormat::Hex.render(65), "0x41");
+        assert_eq!(OutputFormat::Decimal.render(u32::MAX), "4294967295");
+        assert_eq!(OutputFormat::SignedDecimal.render(u32::MAX), "-1");
+    }
+
+    #[test]
+    fn the_control_port_switches_the_format_mid_program() {
+        let mut cpu = load("ldi 3 r1\nsto 63 r1\nldi 9 r2\nsto 63 r2\nhlt\n");
+
+        assert_eq!(cpu.step_n(2), HaltReason::StepLimit);
+        assert_eq!(cpu.output_format, OutputFormat::Ascii);
+
+        // Values that aren't a format leave the current one in place.
+        assert_eq!(cpu.step_n(5), HaltReason::Halted);
+        assert_eq!(cpu.output_format, OutputFormat::Ascii);
+    }
+
+    #[test]
+    fn neg_takes_the_twos_complement() {
+        let (cpu, _) = run("ldi 5 r0\nneg r0\nhlt\n");
+
+        assert_eq!(cpu.registers[0], 0xFFFFFFFB);
+        assert_eq!(cpu.registers[0] as i32, -5);
+        assert_eq!(cpu.flag_register, FLAG_SIGN);
+
+        let (cpu, _) = run("neg r1\nhlt\n");
+
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.flag_register, FLAG_ZERO);
+    }
+
+    #[test]
+    fn quiet_prints_no_per_cycle_lines() {
+        let mut cpu = Processor::new();
+
+        // The per-cycle banner and effect lines are all `Normal`.
+        assert!(cpu.traces(Verbosity::Normal));
+        assert!(!cpu.traces(Verbosity::Verbose));
+
+        cpu.set_verbosity(Verbosity::Quiet);
+
+        assert!(!cpu.traces(Verbosity::Normal));
+        assert!(!cpu.traces(Verbosity::Verbose));
+    }
+
+    #[test]
+    fn status_accessors_reflect_the_halted_machine() {
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+        cpu.load_program(&assembler::assemble("ldi 3 r2\nhlt\n").unwrap());
+
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.program_counter(), 1);
+        assert_eq!(cpu.registers(), &[0, 0, 3, 0]);
+    }
+
+    #[test]
+    fn explain_describes_an_add_with_its_operands_and_result() {
+        let buffer = SharedBuffer::default();
+        let mut cpu = Processor::builder().verbosity(Verbosity::Explain).sink(Box::new(buffer.clone())).build();
+
+        cpu.load_program(&assembler::assemble("ldi 1 r1\nldi 2 r2\nadd r1 r2 r2\nhlt\n").unwrap());
+        cpu.run();
+
+        assert!(buffer.contents().lines().any(|line| line == "ADD: Added R1 (1) and R2 (2), stored 3 in R2."));
+    }
+
+    #[test]
+    fn bit_sets_zero_only_when_the_bit_is_clear() {
+        let (cpu, _) = run("ldi 8 r0\nbit r0 3\nhlt\n");
+
+        assert_eq!(cpu.flag_register & FLAG_ZERO, 0);
+        assert_eq!(cpu.registers()[0], 8);
+
+        let (cpu, _) = run("ldi 7 r0\nbit r0 3\nhlt\n");
+
+        assert_eq!(cpu.flag_register & FLAG_ZERO, FLAG_ZERO);
+        assert_eq!(cpu.registers()[0], 7);
+    }
+
+    #[test]
+    fn fuel_traps_and_resumes_after_refuelling() {
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+        cpu.load_program(&assembler::assemble("ldi 10 r0\nldi 1 r1\nloop:\nsub r0 r1 r0\ncmp 0 r0\njgt loop\nhlt\n").unwrap());
+        cpu.set_fuel(10);
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::OutOfFuel));
+        assert_eq!(cpu.fuel(), Some(0));
+
+        let stopped_at = cpu.registers()[0];
+
+        assert!(stopped_at > 0);
+
+        cpu.set_fuel(100);
+
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers()[0], 0);
+    }
+
+    #[test]
+    fn a_trapped_instruction_uses_no_fuel() {
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+        cpu.load_program(&assembler::assemble(".equ in 60\nlod r0 in\nhlt\n").unwrap());
+        cpu.set_fuel(5);
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::NoInput));
+        assert_eq!(cpu.fuel(), Some(5));
+        assert_eq!(cpu.program_counter(), 0);
+    }
+
+    /// Whether `jump` is taken after `cmp 5 r0` with R0 = 7.
+    fn taken_after_compare(jump: &str) -> bool {
+        let (cpu, _) = run(&format!("ldi 7 r0\ncmp 5 r0\n{} taken\nhlt\ntaken:\nldi 1 r1\nhlt\n", jump));
+
+        cpu.registers()[1] == 1
+    }
+
+    #[test]
+    fn one_compare_answers_every_condition() {
+        assert!(taken_after_compare("jgt"));
+        assert!(taken_after_compare("jge"));
+        assert!(taken_after_compare("jne"));
+        assert!(!taken_after_compare("jlt"));
+        assert!(!taken_after_compare("jle"));
+        assert!(!taken_after_compare("jeq"));
+    }
+
+    #[test]
+    fn a_wrapping_add_sets_carry_and_leaves_the_comparison() {
+        // R0 = 0xFFFFFFFF, from 0 - 1.
+        let (cpu, reason) = run("ldi 1 r1\nsub r0 r1 r0\ncmp 5 r1\nadd r0 r1 r2\nhlt\n");
+
+        assert_eq!(reason, HaltReason::Halted);
+        assert_eq!(cpu.registers()[0], u32::MAX);
+        assert_eq!(cpu.registers()[2], 0);
+        assert_eq!(cpu.flags(), FLAG_CARRY | FLAG_SIGN);
+
+        let (cpu, _) = run("ldi 1 r1\nsub r0 r1 r0\nadd r1 r1 r2\nhlt\n");
+
+        assert_eq!(cpu.registers()[2], 2);
+        assert_eq!(cpu.flags() & FLAG_CARRY, 0);
+
+        let (cpu, _) = run("ldi 1 r1\nsub r0 r1 r0\njcs borrowed\nhlt\nborrowed:\nldi 7 r3\nhlt\n");
+
+        assert_eq!(cpu.registers()[3], 7);
+    }
+
+    #[test]
+    fn step_runs_one_instruction_without_printing() {
+        let buffer = SharedBuffer::default();
+        let mut cpu = Processor::builder().verbosity(Verbosity::Verbose).sink(Box::new(buffer.clone())).build();
+
+        cpu.load_program(&assembler::assemble("ldi 4 r0\ncmp 4 r0\nhlt\n").unwrap());
+
+        assert_eq!(cpu.step(), StepResult::Running);
+        assert_eq!((cpu.program_counter(), cpu.registers()[0]), (1, 4));
+        assert_eq!(cpu.step(), StepResult::Running);
+        assert_eq!(cpu.flags(), FLAG_ZERO);
+        assert_eq!(cpu.step(), StepResult::Stopped(HaltReason::Halted));
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn memory_instructions_disassemble_with_register_and_address() {
+        let program = assembler::assemble("sto 40 r3\nlod r1 41\nlea r2 42\nldx r1 r0\nstx r0 r1\n").unwrap();
+        let listing: Vec<String> = program.into_iter().map(disassemble).collect();
+
+        assert_eq!(listing, ["STORE 40 R3", "LOAD R1 41", "LOAD_ADDR R2 42", "LOAD_INDEXED R1 R0", "STORE_INDEXED R0 R1"]);
+    }
+
+    /// R2 after `op r0 r1 r2` with R0 = 0b1100 and R1 = the given value.
+    fn bitwise(op: &str, r1: u32) -> u32 {
+        let (cpu, _) = run(&format!("ldi 12 r0\nldi {} r1\n{} r0 r1 r2\nhlt\n", r1, op));
+
+        cpu.registers()[2]
+    }
+
+    #[test]
+    fn bitwise_and_shift_instructions_compute_their_results() {
+        assert_eq!(bitwise("and", 0b1010), 0b1000);
+        assert_eq!(bitwise("or", 0b1010), 0b1110);
+        assert_eq!(bitwise("xor", 0b1010), 0b0110);
+        assert_eq!(bitwise("shl", 2), 0b110000);
+        assert_eq!(bitwise("shr", 2), 0b11);
+        assert_eq!(bitwise("shl", 32), 0);
+        assert_eq!(bitwise("shr", 40), 0);
+
+        let (cpu, _) = run("ldi 12 r3\nnot r3\nhlt\n");
+
+        assert_eq!(cpu.registers()[3], !12);
+    }
+
+    #[test]
+    fn the_machine_takes_its_sizes_from_construction() {
+        let cpu = Processor::with_sizes(128, 2);
+
+        assert_eq!((cpu.ram().len(), cpu.registers().len()), (128, 2));
+        assert_eq!((Processor::new().ram().len(), Processor::new().registers().len()), (DEFAULT_RAM_WORDS, DEFAULT_REGISTERS));
+
+        // Running off the end of a 4-word RAM stops at its last cell.
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).ram(4).build();
+
+        cpu.load_program(&assembler::assemble("nop\nnop\nnop\nnop\n").unwrap());
+
+        assert_eq!(cpu.run(), HaltReason::EndOfMemory);
+        assert_eq!(cpu.program_counter(), 3);
+
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).ram(32).build();
+
+        cpu.load_program(&assembler::assemble("ldi 1 r0\nsto 40 r0\nhlt\n").unwrap());
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::AddressOutOfRange(40)));
+        assert_eq!(cpu.program_counter(), 1);
+    }
+
+    #[test]
+    fn jumps_reach_31_and_code_past_it_runs_by_falling_through() {
+        let err = assembler::assemble("jmp 32\n").unwrap_err();
+
+        assert_eq!(err.kind, assembler::ErrorKind::OutOfRange { operand: "32".to_string(), width: 5 });
+
+        let source = format!("{}ldi 7 r0\nhlt\n", "nop\n".repeat(40));
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).ram(128).build();
+
+        cpu.load_program(&assembler::assemble(&source).unwrap());
+
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers()[0], 7);
+        assert_eq!(cpu.program_counter(), 41);
+    }
+
+    #[test]
+    #[should_panic(expected = "Instructions can only name 4 registers.")]
+    fn more_registers_than_instructions_can_name_are_turned_down() {
+        Processor::builder().registers(MAX_REGISTERS + 1).build();
+    }
+
+    #[test]
+    fn jmp_and_a_taken_conditional_jump_land_on_the_same_address() {
+        let mut cpu = load("jmp 3\nnop\nnop\nhlt\n");
+
+        cpu.step();
+
+        assert_eq!(cpu.program_counter(), 3);
+
+        let mut cpu = load("cmp 0 r0\njeq 3\nnop\nhlt\n");
+
+        cpu.step_n(2);
+
+        assert_eq!(cpu.program_counter(), 3);
+
+        // A taken jump back to 0 doesn't underflow.
+        let mut cpu = load("ldi 1 r1\ncmp 0 r0\njeq 0\nhlt\n");
+
+        cpu.step_n(3);
+
+        assert_eq!(cpu.program_counter(), 0);
+
+        let mut cpu = load("nop\njmp 0\n");
+
+        cpu.step_n(2);
+
+        assert_eq!(cpu.program_counter(), 0);
+    }
+
+    #[test]
+    fn a_runaway_loop_stops_at_the_cycle_limit() {
+        let mut cpu = load("ldi 1 r1\nloop:\nadd r0 r1 r0\njmp loop\n");
+
+        assert_eq!(cpu.cycle_limit(), Some(DEFAULT_CYCLE_LIMIT));
+
+        cpu.set_cycle_limit(Some(1000));
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::CycleLimit(1000)));
+        assert_eq!(cpu.cycles, 1000);
+        assert!(!cpu.is_halted());
+    }
+
+    #[test]
+    fn each_conditional_jump_lands_where_its_mnemonic_says() {
+        // R0 = 3, 5 and 7 against 5 leave the flags at LT, EQ and GT in turn.
+        for (value, state) in [(3, FlagState::Less), (5, FlagState::Equal), (7, FlagState::Greater)] {
+            for (jump, wanted) in [("jeq", FlagState::Equal), ("jgt", FlagState::Greater), ("jlt", FlagState::Less)] {
+                let mut cpu = load(&format!("ldi {} r0\ncmp 5 r0\n{} 5\nhlt\nhlt\nhlt\n", value, jump));
+
+                assert_eq!(get_opcode_name(cpu.ram()[2] >> 18), jump);
+
+                cpu.step_n(2);
+
+                assert_eq!(FlagState::from_register(cpu.flag_register), state);
+
+                cpu.step();
+
+                assert_eq!(cpu.program_counter(), if state == wanted { 5 } else { 3 }, "{} with R0 = {}", jump, value);
+            }
+        }
+    }
+
+    #[test]
+    fn a_register_field_past_the_register_file_traps() {
+        let halt = 15 << 18;
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+        cpu.load_program(&[(1 << 18) | (7 << 2) | 1, halt]);
+        cpu.step();
+
+        // A malformed add whose Ra field runs into the unused high bits.
+        cpu.load_program(&[(2 << 18) | (0xFF << 4), halt]);
+        cpu.program_counter = 0;
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::RegisterOutOfRange(0xFF)));
+        assert_eq!(cpu.program_counter(), 0);
+        assert_eq!(cpu.registers(), &[0, 7, 0, 0]);
+    }
+
+    #[test]
+    fn rdpc_loads_its_own_address() {
+        let (cpu, _) = run("nop\nnop\nnop\nrdpc r1\nhlt\n");
+
+        assert_eq!(cpu.registers()[1], 3);
+    }
+
+    #[test]
+    fn every_sink_receives_the_same_output() {
+        let first = SharedBuffer::default();
+        let second = SharedBuffer::default();
+        let mut cpu = Processor::new();
+
+        cpu.add_sink(Box::new(first.clone()));
+        cpu.add_sink(Box::new(second.clone()));
+        cpu.load_program(&assembler::assemble("ldi 4 r0\nsto 62 r0\nhlt\n").unwrap());
+        cpu.run();
+
+        assert!(first.contents().contains("[1]"));
+        assert!(first.contents().contains("4\n"));
+        assert_eq!(first.contents(), second.contents());
+    }
+
+    #[test]
+    fn the_output_port_writes_through_the_sinks() {
+        let buffer = SharedBuffer::default();
+        let mut cpu = quiet();
+
+        cpu.add_sink(Box::new(buffer.clone()));
+        cpu.load_program(&assembler::assemble("ldi 65 r0\nldi 3 r1\nldi 2 r2\nsto 62 r0\nsto 63 r1\nsto 62 r0\nsto 63 r2\nsto 62 r0\nhlt\n").unwrap());
+        cpu.run();
+
+        // Quiet leaves only the program's own output and the final registers.
+        assert!(buffer.contents().starts_with("65\nA0x41\n"));
+    }
+
+    /// The flags after `cmp` sets EQ and a `jump` to the next line runs under `policy`.
+    fn flags_after_jump(policy: FlagClearPolicy, jump: &str) -> u32 {
+        let mut cpu = load(&format!("ldi 5 r0\ncmp 5 r0\n{} 3\nhlt\n", jump));
+
+        cpu.set_flag_clear_policy(policy);
+        cpu.step_n(3);
+
+        cpu.flag_register
+    }
+
+    #[test]
+    fn the_flag_clear_policy_decides_what_a_branch_leaves() {
+        // jeq is taken after an equal compare, jgt is not.
+        assert_eq!(flags_after_jump(FlagClearPolicy::WhenTaken, "jeq"), 0);
+        assert_eq!(flags_after_jump(FlagClearPolicy::WhenTaken, "jgt"), FLAG_ZERO);
+        assert_eq!(flags_after_jump(FlagClearPolicy::Always, "jeq"), 0);
+        assert_eq!(flags_after_jump(FlagClearPolicy::Always, "jgt"), 0);
+        assert_eq!(flags_after_jump(FlagClearPolicy::Never, "jeq"), FLAG_ZERO);
+        assert_eq!(flags_after_jump(FlagClearPolicy::Never, "jgt"), FLAG_ZERO);
+
+        assert_eq!(FlagClearPolicy::default(), FlagClearPolicy::WhenTaken);
+    }
+
+    #[test]
+    fn the_builder_applies_every_setting() {
+        let cpu = Processor::builder()
+            .ram(128)
+            .registers(2)
+            .debug(true)
+            .clock(Clock::Delayed(Duration::from_millis(2)))
+            .fuel(50)
+            .output_format(OutputFormat::Hex)
+            .flag_clear_policy(FlagClearPolicy::Never)
+            .build();
+
+        assert_eq!(cpu.ram.len(), 128);
+        assert_eq!(cpu.registers().len(), 2);
+        assert_eq!(cpu.verbosity, Verbosity::Normal);
+        assert_eq!(cpu.cycle_delay, Duration::from_millis(2));
+        assert_eq!(cpu.fuel(), Some(50));
+        assert_eq!(cpu.output_format, OutputFormat::Hex);
+        assert_eq!(cpu.flag_clear_policy, FlagClearPolicy::Never);
+
+        let cpu = Processor::builder().ram(64).registers(4).debug(false).clock(Clock::Immediate).build();
+
+        assert_eq!(cpu.verbosity, Verbosity::Quiet);
+        assert_eq!(cpu.cycle_delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn a_harvard_store_leaves_the_code_alone() {
+        // The store overwrites the `ldi 7 r1` after it with 0, a `nop`.
+        let source = "ldi 0 r0\nsto 2 r0\nldi 7 r1\nhlt\n";
+
+        let (cpu, _) = run(source);
+
+        assert_eq!(cpu.registers()[1], 0);
+
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).memory_model(MemoryModel::Harvard).build();
+
+        cpu.load_program(&assembler::assemble(source).unwrap());
+
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.ram[2], 0);
+        assert_eq!(cpu.registers()[1], 7);
+    }
+
+    #[test]
+    fn a_quiet_run_prints_nothing_and_returns_why_it_stopped() {
+        assert_eq!(output(Processor::builder(), "ldi 1 r0\nhlt\n"), "");
+
+        let buffer = SharedBuffer::default();
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).fuel(3).sink(Box::new(buffer.clone())).build();
+
+        cpu.load_program(&assembler::assemble("loop:\njmp loop\n").unwrap());
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::OutOfFuel));
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn loop_detection_catches_a_self_loop() {
+        let mut cpu = load("ldi 1 r0\nloop:\njmp loop\n");
+
+        cpu.set_loop_detection(8);
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::NoProgress(1)));
+    }
+
+    #[test]
+    fn loop_detection_survives_resuming_after_a_trap() {
+        let source = "ldi 1 r1\nloop:\nadd r0 r1 r0\njmp loop\n";
+
+        let mut cpu = load(source);
+
+        cpu.set_loop_detection(8);
+        cpu.set_fuel(5);
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::OutOfFuel));
+
+        cpu.set_fuel(5);
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::OutOfFuel));
+
+        let mut cpu = load(source);
+
+        cpu.set_loop_detection(8);
+        cpu.set_cycle_limit(Some(5));
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::CycleLimit(5)));
+
+        cpu.set_cycle_limit(Some(10));
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::CycleLimit(10)));
+    }
+
+    #[test]
+    fn lea_and_indexed_loads_walk_an_array() {
+        let (cpu, _) = run("lea r0 array\nldx r1 r0\nldi 1 r2\nadd r0 r2 r0\nldx r3 r0\nhlt\narray:\n.word 11\n.word 22\n");
+
+        assert_eq!(cpu.registers(), &[7, 11, 1, 22]);
+    }
+
+    #[test]
+    fn an_indexed_store_writes_through_the_address_register() {
+        let (cpu, _) = run("lea r0 slot\nldi 9 r1\nstx r0 r1\nhlt\nslot:\n.word 0\n");
+
+        assert_eq!(cpu.ram[4], 9);
+    }
+
+    #[test]
+    fn the_flag_trace_records_the_compare_and_the_branch_clearing_it() {
+        let program = assembler::assemble("ldi 5 r0\ncmp 5 r0\njeq done\nnop\ndone:\nhlt\n").unwrap();
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).flag_trace().build();
+
+        cpu.load_program(&program);
+        cpu.run();
+
+        assert_eq!(cpu.flag_trace(), &[
+            FlagChange { cycle: 1, program_counter: 1, instruction: program[1], old: FlagState::Clear, new: FlagState::Equal },
+            FlagChange { cycle: 2, program_counter: 2, instruction: program[2], old: FlagState::Equal, new: FlagState::Clear }
+        ]);
+        assert_eq!(cpu.flag_trace()[0].to_string(), format!("cycle 1 [1] {}: clear -> EQ", disassemble(program[1])));
+    }
+
+    #[test]
+    fn a_passed_deadline_stops_a_self_loop() {
+        let mut cpu = load("loop:\njmp loop\n");
+
+        cpu.set_cycle_limit(None);
+        cpu.set_deadline(Instant::now() + Duration::from_millis(20));
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::Timeout));
+        assert!(cpu.cycles > 0);
+    }
+
+    #[test]
+    fn mul_keeps_the_low_bits_and_leaves_the_flags() {
+        let (cpu, _) = run("ldi 6 r0\nldi 7 r1\ncmp 6 r0\nmul r0 r1 r2\nhlt\n");
+
+        assert_eq!(cpu.registers[2], 42);
+        assert_eq!(cpu.flag_register, FLAG_ZERO);
+
+        let (cpu, _) = run("ldi 65535 r0\nmul r0 r0 r1\nmul r1 r1 r2\nhlt\n");
+
+        assert_eq!(cpu.registers[1], 65535 * 65535);
+        assert_eq!(cpu.registers[2], 4294705153);
+    }
+
+    #[test]
+    fn cmoveq_moves_only_after_an_equal_compare() {
+        let (cpu, _) = run("ldi 5 r0\nldi 9 r2\ncmp 5 r0\ncmoveq r1 r2\nhlt\n");
+
+        assert_eq!(cpu.registers()[1], 9);
+
+        let (cpu, _) = run("ldi 6 r0\nldi 9 r2\ncmp 5 r0\ncmoveq r1 r2\ncmovgt r3 r2\nhlt\n");
+
+        assert_eq!(cpu.registers()[1], 0);
+        assert_eq!(cpu.registers()[3], 9);
+    }
+
+    #[test]
+    fn ram_filled_with_poison_reads_it_until_written() {
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).ram_fill(POISON).build();
+
+        cpu.load_program(&assembler::assemble("lod r0 40\nsto 41 r1\nlod r2 41\nhlt\n").unwrap());
+        cpu.run();
+
+        assert_eq!(cpu.registers()[0], POISON);
+        assert_eq!(cpu.registers()[2], 0);
+        assert_eq!(cpu.ram[42], POISON);
+
+        let (cpu, _) = run("hlt\n");
+
+        assert!(cpu.ram[1..].iter().all(|&word| word == 0));
+    }
+
+    #[test]
+    fn the_port_encoding_sets_the_bytes_written() {
+        let source = "ldi 16706 r0\nsto 62 r0\nhlt\n";
+        let bytes = |encoding| output(Processor::builder().port_encoding(encoding), source).into_bytes();
+
+        assert_eq!(bytes(PortEncoding::Text), b"16706\n");
+        assert_eq!(bytes(PortEncoding::Byte), [0x42]);
+        assert_eq!(bytes(PortEncoding::Word(Endianness::Little)), [0x42, 0x41, 0x00, 0x00]);
+        assert_eq!(bytes(PortEncoding::Word(Endianness::Big)), [0x00, 0x00, 0x41, 0x42]);
+    }
+
+    #[test]
+    fn state_log_has_one_entry_per_cycle() {
+        let mut cpu = load("ldi 3 r0\nldi 1 r1\nloop:\nsto 20 r0\nsub r0 r1 r0\ncmp 0 r0\njgt loop\nhlt\n");
+        let (reason, log) = cpu.run_with_state_log(1000);
+
+        assert_eq!(reason, HaltReason::Halted);
+        assert_eq!(log.len() as u64, cpu.cycles);
+        assert_eq!(log.last(), Some(&cpu.state()));
+        assert_eq!(log[2].ram[20], 3);
+
+        let (_, log) = cpu.run_with_state_log(1000);
+
+        assert!(log.is_empty());
+
+        let mut cpu = load("loop:\njmp loop\n");
+
+        cpu.set_fuel(5);
+
+        let (reason, log) = cpu.run_with_state_log(1000);
+
+        assert_eq!(reason, HaltReason::Trap(Trap::OutOfFuel));
+        assert_eq!(log.len(), 5);
+    }
+
+    #[test]
+    fn dbg_pauses_the_run_and_resuming_finishes_it() {
+        let mut cpu = load("ldi 1 r0\ndbg\nldi 2 r1\nhlt\n");
+
+        assert_eq!(cpu.run(), HaltReason::DebugTrap(1));
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.program_counter(), 2);
+        assert_eq!(cpu.registers()[1], 0);
+
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers()[0..2], [1, 2]);
+    }
+}
@@ -0,0 +1,184 @@
+//! Best-effort register dataflow warnings, found by static analysis of the control
+//! flow graph from address 0. The analysis follows both ways out of a
+//! conditional jump whatever the flags would be, so it can warn about a path that
+//! never runs, and it can miss problems in code reached only through data.
+
+use std::fmt;
+
+use crate::{jump_offset, OPERAND_MASK};
+use crate::pipeline::{reads, writes};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// The register is written at `address` but overwritten on every path before
+    /// anything reads it.
+    DeadWrite { address: usize, register: u32 },
+    /// The register is read at `address` but some path from the start reaches it
+    /// without writing the register first, so it may still hold its reset value.
+    ReadBeforeWrite { address: usize, register: u32 }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::DeadWrite { address, register } => {
+                write!(f, "[{}] R{} is written but overwritten before it is read", address, register)
+            }
+            Warning::ReadBeforeWrite { address, register } => {
+                write!(f, "[{}] R{} may be read before anything writes it", address, register)
+            }
+        }
+    }
+}
+
+/// Registers as a bitmask, bit `n` for Rn.
+type RegisterSet = u8;
+
+const ALL_REGISTERS: RegisterSet = 0b1111;
+
+fn register_set(registers: impl IntoIterator<Item = u32>) -> RegisterSet {
+    registers.into_iter().fold(0, |set, register| set | (1 << (register & 0b11)))
+}
+
+/// Registers the instruction is sure to overwrite. A conditional move may leave its
+/// destination alone, so it doesn't count.
+fn defines(instruction: u32) -> RegisterSet {
+    match instruction >> 18 {
+        24..=26 => 0,
+        _ => register_set(writes(instruction))
+    }
+}
+
+/// Addresses execution can continue at after the instruction at `address`, with
+/// `None` standing for leaving the program (halting or running off its end).
+fn successors(address: usize, instruction: u32, length: usize) -> Vec<Option<usize>> {
+    let operand = instruction & OPERAND_MASK;
+    let next = |target: i64| (0..length as i64).contains(&target).then_some(target as usize);
+
+    match instruction >> 18 {
+        15 => vec![None],
+        5 => vec![next((operand & 0b11111) as i64)],
+        22 => vec![next(address as i64 + jump_offset(operand) as i64)],
+        6..=8 | 18..=20 | 28 => vec![next((operand & 0b11111) as i64), next(address as i64 + 1)],
+        _ => vec![next(address as i64 + 1)]
+    }
+}
+
+/// Analyzes `program` (a loaded image, code and data) and returns its warnings in
+/// address order.
+pub fn analyze(program: &[u32]) -> Vec<Warning> {
+    let length = program.len();
+
+    // Find the reachable instructions and their successors.
+    let mut reachable = vec![false; length];
+    let mut edges: Vec<Vec<Option<usize>>> = vec![Vec::new(); length];
+    let mut worklist = if length > 0 { vec![0] } else { Vec::new() };
+
+    while let Some(address) = worklist.pop() {
+        if reachable[address] {
+            continue;
+        }
+
+        reachable[address] = true;
+        edges[address] = successors(address, program[address], length);
+        worklist.extend(edges[address].iter().flatten());
+    }
+
+    // Forward: registers that may not have been written yet on entry to each address.
+    let mut unwritten: Vec<RegisterSet> = vec![0; length];
+    let mut changed = length > 0;
+
+    if length > 0 {
+        unwritten[0] = ALL_REGISTERS;
+    }
+
+    while changed {
+        changed = false;
+
+        for address in (0..length).filter(|&address| reachable[address]) {
+            let out = unwritten[address] & !defines(program[address]);
+
+            for &successor in edges[address].iter().flatten() {
+                if unwritten[successor] | out != unwritten[successor] {
+                    unwritten[successor] |= out;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    // Backward: registers whose value may still be read after each address. Every
+    // register counts as read on leaving the program, since the final register
+    // state is shown after a run.
+    let mut live_out: Vec<RegisterSet> = vec![0; length];
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for address in (0..length).rev().filter(|&address| reachable[address]) {
+            let live = edges[address].iter().fold(0, |live, successor| match successor {
+                Some(successor) => {
+                    let instruction = program[*successor];
+
+                    live | register_set(reads(instruction)) | (live_out[*successor] & !defines(instruction))
+                }
+                None => live | ALL_REGISTERS
+            });
+
+            if live != live_out[address] {
+                live_out[address] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut warnings = Vec::new();
+
+    for address in (0..length).filter(|&address| reachable[address]) {
+        let instruction = program[address];
+
+        for register in reads(instruction) {
+            if unwritten[address] & register_set([register]) != 0 {
+                warnings.push(Warning::ReadBeforeWrite { address, register });
+            }
+        }
+
+        if let Some(register) = writes(instruction) {
+            let written = register_set([register]);
+
+            if defines(instruction) & written != 0 && live_out[address] & written == 0 {
+                warnings.push(Warning::DeadWrite { address, register });
+            }
+        }
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn an_overwritten_register_is_a_dead_write() {
+        let program = assemble("ldi 1 r0\nldi 2 r0\nldi 0 r1\nldi 0 r2\nldi 0 r3\nhlt\n").unwrap();
+
+        assert_eq!(analyze(&program), [Warning::DeadWrite { address: 0, register: 0 }]);
+    }
+
+    #[test]
+    fn a_register_read_on_a_path_that_skips_its_write_is_flagged() {
+        let program = assemble("ldi 0 r1\nldi 0 r2\nldi 0 r3\ncmp 0 r1\njeq 6\nldi 5 r0\nsto 40 r0\nhlt\n").unwrap();
+
+        assert_eq!(analyze(&program), [Warning::ReadBeforeWrite { address: 6, register: 0 }]);
+    }
+
+    #[test]
+    fn a_clean_program_has_no_warnings() {
+        let program = assemble("ldi 3 r0\nldi 1 r1\nldi 0 r2\nldi 0 r3\nloop:\nsub r0 r1 r0\ncmp 0 r0\njgt loop\nhlt\n").unwrap();
+
+        assert_eq!(analyze(&program), []);
+    }
+}
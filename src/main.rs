@@ -1,234 +1,551 @@
-use std::{thread, time::Duration};
-
-struct Processor {
-    registers: [i32; 4],
-    program_counter: usize,
-    ram: [i32; 64],
-    flag_register: i32,
-    halt: bool
+// Instruction literals are grouped by field (opcode, operand sub-fields), not by nibble.
+#![allow(clippy::unusual_byte_groupings)]
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use std::{env, fs, process};
+
+use cpusim::assembler::{self, AssembleOptions};
+use cpusim::binary::{self, BinaryFormat};
+use cpusim::replay::Recording;
+use cpusim::{debugger, examples, lint, multicycle};
+use cpusim::{HaltReason, Processor, Trap, Verbosity, DEFAULT_CYCLE_LIMIT, DEFAULT_RAM_WORDS, DEFAULT_REGISTERS, MAX_REGISTERS, POISON};
+
+fn demo() -> Vec<u32> {
+    // 0b_0000_000000000000000000
+
+    vec![
+        0b_0001_0000000000000001_01,
+        0b_0001_0000000000000001_10,
+        0b_0010_000000000000_01_10_10,
+        0b_0100_1000000000000000_10,
+        0b_1000_0000000000000_00010,
+        0b_1111_000000000000000000
+    ]
+}
+
+fn parse_format(name: Option<&String>, flag: &str) -> Result<BinaryFormat, String> {
+    let name = name.ok_or_else(|| format!("missing {} <raw|raw-be|headered|hex|ihex|c-header>", flag))?;
+
+    BinaryFormat::from_name(name).ok_or_else(|| format!("unknown format '{}' for {}", name, flag))
+}
+
+/// `cpusim convert --from <fmt> --to <fmt> <in> <out>`
+fn convert(args: &[String]) -> Result<(), String> {
+    let mut from = None;
+    let mut to = None;
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = args.next(),
+            "--to" => to = args.next(),
+            _ => paths.push(arg)
+        }
+    }
+
+    let from = parse_format(from, "--from")?;
+    let to = parse_format(to, "--to")?;
+
+    let [input, output] = paths[..] else {
+        return Err("usage: cpusim convert --from <fmt> --to <fmt> <in> <out>".to_string());
+    };
+
+    let bytes = fs::read(input).map_err(|err| format!("{}: {}", input, err))?;
+    let program = binary::decode(from, &bytes).map_err(|err| format!("{}: {}", input, err))?;
+
+    fs::write(output, binary::encode(to, &program)).map_err(|err| format!("{}: {}", output, err))
 }
 
-// ____________     0000      00000000000000000
-//                   4               18
-//    EXTRA       OPCODE            DATA
+/// `cpusim hexdump <file>`
+fn hexdump(args: &[String]) -> Result<(), String> {
+    let path = args.first().ok_or("usage: cpusim hexdump <file>")?;
+    let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+
+    print!("{}", binary::hexdump(&bytes));
 
-fn print_as_assembly(instruction: i32) {
-    let opcode = instruction >> 18;
-    let operand = instruction & (!(0b1111 << 18));
+    Ok(())
+}
+
+/// `cpusim multicycle`: the demo's execution broken into multi-cycle micro-steps.
+fn multicycle() -> Result<(), String> {
+    let mut cpu = Processor::new();
 
-    let mut final_string = String::new();
+    cpu.set_verbosity(Verbosity::Quiet);
+    cpu.load_program(&demo());
 
-    match opcode {
-        0 => { final_string.push_str("NO-OP ")},
-        1 => { 
-            final_string.push_str("LOAD_IMMED ");
+    let (_, stream) = cpu.run_recording();
 
-            let immediate_value = operand >> 2;
-            let target_register = operand & (!(0b1111 << 2));
+    print!("{}", multicycle::analyze(&stream));
+
+    Ok(())
+}
+
+/// `cpusim debug [--poison] <file>`
+fn debug(args: &[String]) -> Result<(), String> {
+    let poison = args.iter().any(|arg| arg == "--poison");
+    let path = args.iter().find(|arg| !arg.starts_with("--")).ok_or("usage: cpusim debug [--poison] <file>")?;
+    let program = assembler::assemble_from_file(path, &AssembleOptions::default()).map_err(|err| err.to_string())?;
+
+    let mut cpu = Processor::new();
 
-            final_string.push_str(&i32::to_string(&immediate_value));
-            final_string.push_str(" R");
-            final_string.push_str(&i32::to_string(&target_register));
-        },
-        2 => { final_string.push_str("ADD ")},
-        3 => { final_string.push_str("SUB ")},
-        4 => { final_string.push_str("CMP_IMMED ")},
-        5 => { final_string.push_str("JMP ")},
-        6 => { final_string.push_str("JMP_EQ ")},
-        7 => { final_string.push_str("JMP_GT ")},
-        8 => { final_string.push_str("JMP_LT ")},
-        15 => { final_string.push_str("HALT")}
-        _ => {}
+    if poison {
+        cpu.fill_ram(POISON);
     }
 
-    println!("{}", final_string);
+    cpu.set_verbosity(Verbosity::Quiet);
+    cpu.load_program(&program);
+
+    let stdin = io::stdin();
+
+    debugger::Debugger::new(cpu).run(stdin.lock(), &mut io::stdout()).map_err(|err| err.to_string())
 }
 
-impl Processor {
-    fn new() -> Processor {
-        Processor {
-            registers: [0;4],
-            program_counter: 0,
-            ram: [0;64],
-            flag_register: -1,
-            halt: false
-        }
+/// `cpusim lint <file>`
+fn lint(args: &[String]) -> Result<(), String> {
+    let path = args.first().ok_or("usage: cpusim lint <file>")?;
+    let program = assembler::assemble_from_file(path, &AssembleOptions::default()).map_err(|err| err.to_string())?;
+
+    for warning in lint::analyze(&program) {
+        println!("{}: warning: {}", path, warning);
     }
-    
-    fn load_program(&mut self, program:&[i32]) {
-        for (i, &instruction) in program.iter().enumerate() {
-            self.ram[i] = instruction;
-        }
+
+    Ok(())
+}
+
+/// `cpusim example <name>`
+fn example(args: &[String]) -> Result<(), String> {
+    let names: Vec<&str> = examples::EXAMPLES.iter().map(|(name, _)| *name).collect();
+    let usage = format!("usage: cpusim example <{}>", names.join("|"));
+
+    let name = args.first().ok_or_else(|| usage.clone())?;
+    let source = examples::find(name).ok_or_else(|| format!("unknown example '{}'\n{}", name, usage))?;
+
+    let program = assembler::assemble(source).map_err(|err| format!("example {}: {}", name, err))?;
+
+    let mut cpu = Processor::new();
+
+    cpu.set_verbosity(Verbosity::Quiet);
+    cpu.load_program(&program);
+    cpu.run();
+
+    Ok(())
+}
+
+/// `cpusim replay [--debug] <file>`: runs a recording written by `--record`.
+fn replay(args: &[String]) -> Result<(), String> {
+    let debug = args.iter().any(|arg| arg == "--debug");
+    let path = args.iter().find(|arg| !arg.starts_with("--")).ok_or("usage: cpusim replay [--debug] <file>")?;
+
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let recording: Recording = text.parse().map_err(|err| format!("{}: {}", path, err))?;
+
+    if recording.state.ram.is_empty() {
+        return Err(format!("{}: the recording has no RAM", path));
     }
 
-    fn fetch_instruction(&mut self) -> i32 {
-        self.ram[self.program_counter]
+    if recording.state.registers.len() > MAX_REGISTERS {
+        return Err(format!("{}: the recording has more than {} registers", path, MAX_REGISTERS));
     }
 
-    fn execute_instruction(&mut self) {
-        let instruction = self.fetch_instruction();
+    let mut cpu = Processor::builder()
+        .debug(debug)
+        .sizes(recording.state.ram.len(), recording.state.registers.len())
+        .build();
 
-        let opcode = instruction >> 18;
-        let operand = instruction & (!(0b1111 << 18));
+    recording.restore(&mut cpu).map_err(|err| format!("{}: {}", path, err))?;
 
-        print_as_assembly(instruction);
+    if let HaltReason::Trap(trap) = cpu.run() {
+        return Err(format!("{}: trapped: {:?}", path, trap));
+    }
 
-        println!();
+    Ok(())
+}
 
-        println!("OPCODE: {:b}\nOPERAND: {:b}", opcode, operand);
+const USAGE: &str = "usage: cpusim [--input] <file.asm|file.bin> [--output <file.bin>] [--run] [--debug] \
+                     [--interactive] [--stats] [--delay <ms>] [--max-cycles <n>] [--ram <words>] [--registers <n>] [--pic] [-D NAME=value] [--feed <n,n,...>] [--seed <n>] [--record <file>]";
 
-        match opcode {
-            1 => {
-                let immediate_value = operand >> 2;
-                let target_register = operand & (!(0b1111 << 2));
-                self.registers[target_register as usize] = immediate_value;
+/// Parses the number after a command line option.
+fn parse_count<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or(USAGE)?;
 
-                println!("REG[{}] <- {}", target_register, self.registers[target_register as usize]);
-            }
-            2 => {
-                let reg_a = operand >> 4;
-                let reg_b = (operand & 0b001100) >> 2;
-                let reg_c = operand & 0b000011;
-
-                self.registers[reg_c as usize] = self.registers[reg_a as usize] + self.registers[reg_b as usize];
- 
-                println!("REG[{}] <- {}", reg_c, self.registers[reg_c as usize]);
+    value.parse().map_err(|_| format!("{}: '{}' is not a number", flag, value))
+}
+
+/// Reads a raw little-endian binary, as written by `--output`.
+fn load_binary(path: &str) -> Result<Vec<u32>, String> {
+    let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+
+    binary::bin_raw_as_machine_code(&bytes).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// `cpusim [--input] <file.asm> ...`: assembles a file (or loads a `.bin` as is),
+/// writes it out as a raw binary with `--output`, and runs it with `--run` or when
+/// there is no output file. `--debug` traces every cycle and `--delay` pauses after
+/// each one; `--interactive` runs it under the debugger instead, one command at a
+/// time from stdin. `--stats` ends the run with how many instructions of each
+/// kind executed. `--feed` queues values for the input port and `--seed` seeds the
+/// random port; `--record` saves all of that with the starting state, for `cpusim
+/// replay`. `--max-cycles` changes how many instructions run before the
+/// machine gives up on the program (0 for no limit). `--ram` and `--registers`
+/// size the machine.
+fn assemble_and_run(args: &[String]) -> Result<(), String> {
+    let mut input = None;
+    let mut output = None;
+    let mut run = false;
+    let mut debug = false;
+    let mut interactive = false;
+    let mut stats = false;
+    let mut delay = Duration::ZERO;
+    let mut cycle_limit = Some(DEFAULT_CYCLE_LIMIT);
+    let mut ram_words = DEFAULT_RAM_WORDS;
+    let mut registers = DEFAULT_REGISTERS;
+    let mut options = AssembleOptions::default();
+    let mut feed = Vec::new();
+    let mut seed = None;
+    let mut record = None;
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => input = Some(args.next().ok_or(USAGE)?),
+            "--output" => output = Some(args.next().ok_or(USAGE)?),
+            "--run" => run = true,
+            "--debug" => debug = true,
+            "--interactive" | "-i" => interactive = true,
+            "--stats" => stats = true,
+            "--delay" => delay = Duration::from_millis(parse_count(arg, args.next())?),
+            "--max-cycles" => cycle_limit = Some(parse_count(arg, args.next())?).filter(|&limit| limit != 0),
+            "--ram" => ram_words = parse_count(arg, args.next())?,
+            "--registers" => registers = parse_count(arg, args.next())?,
+            "--pic" => options.pic = true,
+            "--feed" => {
+                for value in args.next().ok_or(USAGE)?.split(',') {
+                    feed.push(value.trim().parse().map_err(|_| format!("--feed: '{}' is not a number", value))?);
+                }
             }
-            3 => {
-                let reg_a = operand >> 4;
-                let reg_b = (operand & 0b001100) >> 2;
-                let reg_c = operand & 0b000011;
-
-                self.registers[reg_c as usize] = self.registers[reg_a as usize] - self.registers[reg_b as usize];
- 
-                println!("REG[{}] <- {}", reg_c, self.registers[reg_c as usize]);
+            "--seed" => seed = Some(parse_count(arg, args.next())?),
+            "--record" => record = Some(args.next().ok_or(USAGE)?),
+            "-D" => {
+                let define = args.next().ok_or(USAGE)?;
+                let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+                let value = value.parse().map_err(|_| format!("-D {}: '{}' is not a number", name, value))?;
+
+                options.defines.insert(name.to_string(), value);
             }
-            4 => {
-                let immed_compare = operand >> 2; 
-                let register_addr = operand & (0b11);
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'\n{}", arg, USAGE)),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(USAGE.to_string())
+        }
+    }
 
-                let result = immed_compare - self.registers[register_addr as usize];
+    let input = input.ok_or(USAGE)?;
+    let program = if Path::new(input).extension().is_some_and(|extension| extension == "bin") {
+        load_binary(input)?
+    }
+    else {
+        assembler::assemble_from_file(input, &options).map_err(|err| err.to_string())?
+    };
 
-                if result > 0 {
-                    self.flag_register = 1;
-                }
-                else if result == 0 {
-                    self.flag_register = 0;
-                }
-                else if result < 0 {
-                    self.flag_register = 2;
-                }
-                else {
-                    self.flag_register = -1;
-                }
+    if let Some(output) = output {
+        fs::write(output, binary::machine_code_as_bin_raw(&program)).map_err(|err| format!("{}: {}", output, err))?;
+    }
 
-                println!("CMP -> [{}]", self.flag_register);
-            }
-            5 => {
-                let jump_addr = operand & (0b11111);
+    if !(interactive || run || output.is_none()) {
+        return Ok(());
+    }
 
-                self.program_counter = jump_addr as usize;
+    if ram_words == 0 {
+        return Err("--ram: RAM needs at least one word".to_string());
+    }
 
-                println!("JMP -> [{}]", self.program_counter);
-            }
-            6 => {
-                let jump_addr = operand & (0b11111);
+    if registers > MAX_REGISTERS {
+        return Err(format!("--registers: instructions can only name {} registers", MAX_REGISTERS));
+    }
 
-                if self.flag_register == 0 {
-                    self.program_counter = jump_addr as usize - 1;
-                    self.flag_register = -1;
-                }
-            }
-            7 => {
-                let jump_addr = operand & (0b11111);
+    if program.len() > ram_words {
+        return Err(format!("{}: the program is {} words but RAM holds {}", input, program.len(), ram_words));
+    }
 
-                if self.flag_register == 1 {
-                    self.program_counter = jump_addr as usize - 1;
-                    self.flag_register = -1;
-                }
-            }
-            8 => {
-                let jump_addr = operand & (0b11111);
+    let mut cpu = Processor::builder()
+        .debug(debug && !interactive)
+        .cycle_delay(delay)
+        .cycle_limit(cycle_limit)
+        .ram(ram_words)
+        .registers(registers)
+        .build();
 
-                if self.flag_register == 2 {
-                    self.program_counter = jump_addr as usize - 1;
-                    self.flag_register = -1;
-                }
-            }
-            15 => {
-                self.halt = true;
-            }
-            _ => {
-                return;
-            }
+    if stats {
+        cpu.enable_execution_trace();
+    }
 
-        }
+    cpu.load_program(&program);
 
+    for value in feed {
+        cpu.push_input(value);
+    }
+
+    if let Some(seed) = seed {
+        cpu.set_random_seed(seed);
+    }
+
+    if let Some(record) = record {
+        fs::write(record, Recording::capture(&cpu).to_string()).map_err(|err| format!("{}: {}", record, err))?;
+    }
+
+    if interactive {
+        let stdin = io::stdin();
+
+        return debugger::Debugger::new(cpu).run(stdin.lock(), &mut io::stdout()).map_err(|err| err.to_string());
+    }
+
+    match cpu.run() {
+        HaltReason::Trap(Trap::CycleLimit(limit)) => Err(format!("{}: exceeded {} cycles", input, limit)),
+        HaltReason::Trap(trap) => Err(format!("{}: trapped: {:?}", input, trap)),
+        _ => Ok(())
     }
 }
 
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let result = match args.get(1).map(String::as_str) {
+        Some("convert") => convert(&args[2..]),
+        Some("debug") => debug(&args[2..]),
+        Some("example") => example(&args[2..]),
+        Some("hexdump") => hexdump(&args[2..]),
+        Some("lint") => lint(&args[2..]),
+        Some("multicycle") => multicycle(),
+        Some("replay") => replay(&args[2..]),
+        Some(_) => assemble_and_run(&args[1..]),
+        None => {
+            let mut cpu = Processor::new();
+
+            // for ins in program {
+            //     print_as_assembly(ins);
+            // }
+
+            // thread::sleep(Duration::from_secs(5));
 
-fn assembler(instruction: String) {
-    let terms: Vec<&str> = instruction.split_whitespace().collect();
+            cpu.load_program(&demo());
 
-    let mut output_ins = 0;
+            cpu.run();
 
-    for (i, &term) in terms.iter().enumerate() {
-        match term {
-            "load" => {
-                output_ins = 0b0001
-            },
-            "add" => {
-                output_ins = 0b0010
-            },
-            "sub" => {
-                output_ins = 0b0011
-            },
-            _ => {}
+            Ok(())
         }
+    };
+
+    if let Err(message) = result {
+        eprintln!("error: {}", message);
+        process::exit(1);
     }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The demo, as the assembler would write it.
+    const DEMO_SOURCE: &str = "
+ldi 1 r1
+ldi 1 r2
+loop:
+add r1 r2 r2
+cmp 32768 r2
+jlt loop
+hlt
+";
+
+    /// A path in the system temp directory unique to this test process.
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("cpusim-{}-{}", process::id(), name)).to_string_lossy().into_owned()
+    }
 
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
 
-}
+    fn quiet() -> Processor {
+        Processor::builder().verbosity(Verbosity::Quiet).build()
+    }
 
-fn main() {
-    let mut cpu = Processor::new();
+    #[test]
+    fn step_n_advances_the_demo_exactly_that_far() {
+        let mut cpu = quiet();
 
-    // 0b_0000_000000000000000000
+        cpu.load_program(&demo());
 
-    let program = [
-        0b_0001_0000000000000001_01,
-        0b_0001_0000000000000001_10,
-        0b_0010_000000000000_01_10_10,
-        0b_0100_1000000000000000_10,
-        0b_0111_0000000000000_00010,
-        0b_1111_000000000000000000
-    ];
+        assert_eq!(cpu.step_n(3), HaltReason::StepLimit);
+        assert_eq!(cpu.program_counter(), 3);
+        assert_eq!(cpu.registers(), &[0, 1, 2, 0]);
+    }
 
-    // for ins in program {
-    //     print_as_assembly(ins);
-    // }
+    #[test]
+    fn the_demo_survives_intel_hex() {
+        assert_eq!(binary::from_intel_hex(&binary::to_intel_hex(&demo())).unwrap(), demo());
+    }
 
-    // thread::sleep(Duration::from_secs(5));
+    #[test]
+    fn the_demo_source_matches_the_demo_binary() {
+        let reference = std::env::temp_dir().join(format!("cpusim-{}-demo.bin", process::id()));
 
-    cpu.load_program(&program);
+        fs::write(&reference, binary::machine_code_as_bin_raw(&demo())).unwrap();
 
-    loop {
-        println!("[{}]", cpu.program_counter);
+        assembler::assert_matches_reference(DEMO_SOURCE, &reference);
 
-        cpu.fetch_instruction();
+        let mismatch = std::panic::catch_unwind(|| assembler::assert_matches_reference("ldi 2 r1\n", &reference));
 
-        cpu.execute_instruction();
+        fs::remove_file(&reference).unwrap();
 
-        if cpu.program_counter == 63 || cpu.halt {
-            break;
-        }
+        let message = mismatch.unwrap_err().downcast::<String>().unwrap();
+
+        assert!(message.contains("at word 0"), "{}", message);
+    }
+
+    #[test]
+    fn the_demo_disassembles_to_its_mnemonics() {
+        let listing: Vec<String> = demo().into_iter().map(cpusim::disassemble).collect();
+
+        assert_eq!(listing, [
+            "LOAD_IMMED 1 R1",
+            "LOAD_IMMED 1 R2",
+            "ADD R1, R2 -> R2",
+            "CMP_IMMED 32768 R2",
+            "JMP_LT 2",
+            "HALT"
+        ]);
+    }
+
+    #[test]
+    fn the_cli_assembles_the_input_to_the_output() {
+        let input = temp_path("cli.asm");
+        let output = temp_path("cli.bin");
+
+        fs::write(&input, DEMO_SOURCE).unwrap();
+
+        assert_eq!(assemble_and_run(&args(&["--input", &input, "--output", &output])), Ok(()));
+        assert_eq!(fs::read(&output).unwrap(), binary::machine_code_as_bin_raw(&demo()));
 
-        cpu.program_counter += 1;
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&output).unwrap();
 
-        println!();
+        let missing = temp_path("missing.asm");
 
-        // thread::sleep(Duration::from_secs(1));
+        assert_eq!(assemble_and_run(&args(&[&missing])), Err(format!("{}: file not found", missing)));
+        assert!(assemble_and_run(&args(&[&missing, "--frob"])).unwrap_err().starts_with("unknown option '--frob'"));
+        assert_eq!(assemble_and_run(&args(&["--run"])), Err(USAGE.to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn a_recorded_run_replays_from_the_cli() {
+        let input = temp_path("echo.asm");
+        let recording = temp_path("echo.rec");
+
+        fs::write(&input, ".equ in 60\nlod r0 in\nlod r1 in\nhlt\n").unwrap();
+
+        let recorded = assemble_and_run(&args(&[&input, "--feed", "4, 5", "--seed", "7", "--record", &recording]));
+        let saved: Recording = fs::read_to_string(&recording).unwrap().parse().unwrap();
+        let replayed = replay(&args(&[&recording]));
+        let bad_feed = assemble_and_run(&args(&[&input, "--feed", "4,x"]));
+
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&recording).unwrap();
+
+        assert_eq!(recorded, Ok(()));
+        assert_eq!((saved.random_seed, saved.input), (7, vec![4, 5]));
+        assert_eq!(replayed, Ok(()));
+        assert_eq!(bad_feed, Err("--feed: 'x' is not a number".to_string()));
+    }
+
+    #[test]
+    fn a_binary_file_loads_back_as_written() {
+        let path = temp_path("load.bin");
+
+        fs::write(&path, binary::machine_code_as_bin_raw(&demo())).unwrap();
+
+        assert_eq!(load_binary(&path), Ok(demo()));
+
+        fs::write(&path, [0, 0, 4, 0, 1]).unwrap();
+
+        let err = load_binary(&path).unwrap_err();
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(err.ends_with("1 trailing bytes would be ignored"), "{}", err);
+    }
+
+    #[test]
+    fn the_demo_trace_counts_every_pass_of_the_loop() {
+        let mut cpu = Processor::builder().verbosity(Verbosity::Quiet).execution_trace().build();
+
+        cpu.load_program(&demo());
+
+        assert_eq!(cpu.run(), HaltReason::Halted);
+
+        // R2 climbs from 1 to 32768, one add per pass.
+        let summary = cpu.trace_summary().unwrap();
+
+        assert_eq!(summary.cycles, 2 + 3 * 32767 + 1);
+        assert_eq!(summary.counts["add"], 32767);
+        assert_eq!(summary.counts["jlt"], 32767);
+        assert_eq!(summary.counts["ldi"], 2);
+
+        let last = cpu.execution_trace().last().unwrap();
+
+        assert_eq!((last.program_counter, last.mnemonic), (5, "hlt"));
+        assert_eq!(last.registers, [0, 1, 32768, 0]);
+    }
+
+    #[test]
+    fn a_commented_demo_assembles_to_the_same_words() {
+        let source = "
+; The demo, annotated.
+
+    ldi 1 r1        ; the step
+    ldi 1 r2        # the counter
+
+loop:               ; climb until the counter reaches 2^15
+    add r1 r2 r2
+    cmp 32768 r2    ; still below?
+    jlt loop
+
+# done
+    hlt
+";
+
+        assert_eq!(assembler::assemble(source).unwrap(), demo());
+        assert_eq!(assembler::assemble(DEMO_SOURCE).unwrap(), demo());
+    }
+
+    #[test]
+    fn the_cli_reports_a_program_that_exceeds_its_cycles() {
+        let input = temp_path("runaway.asm");
+
+        fs::write(&input, "loop:\njmp loop\n").unwrap();
+
+        let result = assemble_and_run(&args(&[&input, "--max-cycles", "500"]));
+
+        fs::remove_file(&input).unwrap();
+
+        assert_eq!(result, Err(format!("{}: exceeded 500 cycles", input)));
+    }
+
+    #[test]
+    fn the_cli_turns_down_more_registers_than_instructions_can_name() {
+        let input = temp_path("registers.asm");
+
+        fs::write(&input, "hlt\n").unwrap();
+
+        let too_many = assemble_and_run(&args(&[&input, "--registers", "5"]));
+        let fewer = assemble_and_run(&args(&[&input, "--registers", "2"]));
+
+        fs::remove_file(&input).unwrap();
+
+        assert_eq!(too_many, Err("--registers: instructions can only name 4 registers".to_string()));
+        assert_eq!(fewer, Ok(()));
+    }
+}
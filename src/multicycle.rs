@@ -0,0 +1,103 @@
+//! A teaching model of a multi-cycle datapath. Each instruction is broken into the
+//! micro-steps it would need if the stages took a cycle each; like the pipeline
+//! model, it annotates an executed instruction stream and changes nothing about
+//! how the simulation runs.
+
+use std::fmt;
+
+use crate::get_opcode_name;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicroStep {
+    Fetch,
+    Decode,
+    /// The ALU, comparator or branch unit does its work.
+    Execute,
+    /// A RAM read or write, including the output port.
+    Memory,
+    /// The result is written back to the register file.
+    Writeback
+}
+
+/// The micro-steps one instruction takes, in order.
+pub fn micro_steps(instruction: u32) -> Vec<MicroStep> {
+    use MicroStep::*;
+
+    let mut steps = vec![Fetch, Decode];
+
+    steps.extend_from_slice(match instruction >> 18 {
+        1 | 21 | 23 => &[Writeback][..],
+        2 | 3 | 11..=14 | 16 | 24..=26 | 29 | 30 | 33 => &[Execute, Writeback],
+        4..=8 | 17..=20 | 22 | 28 => &[Execute],
+        9 | 32 => &[Memory],
+        10 | 31 => &[Memory, Writeback],
+        _ => &[]
+    });
+
+    steps
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiCycleReport {
+    /// Each executed instruction word alongside its micro-steps.
+    pub instructions: Vec<(u32, Vec<MicroStep>)>,
+    pub total_micro_steps: usize
+}
+
+/// Breaks `stream` (instruction words in the order they executed) into micro-steps.
+pub fn analyze(stream: &[u32]) -> MultiCycleReport {
+    let instructions: Vec<(u32, Vec<MicroStep>)> =
+        stream.iter().map(|&instruction| (instruction, micro_steps(instruction))).collect();
+    let total_micro_steps = instructions.iter().map(|(_, steps)| steps.len()).sum();
+
+    MultiCycleReport { instructions, total_micro_steps }
+}
+
+impl fmt::Display for MultiCycleReport {
+    /// One line per opcode executed, in order of first appearance, then the totals.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut opcodes: Vec<(u32, &[MicroStep], usize)> = Vec::new();
+
+        for (instruction, steps) in &self.instructions {
+            let opcode = instruction >> 18;
+
+            match opcodes.iter_mut().find(|(seen, _, _)| *seen == opcode) {
+                Some((_, _, count)) => *count += 1,
+                None => opcodes.push((opcode, steps, 1))
+            }
+        }
+
+        for (opcode, steps, count) in opcodes {
+            let names: Vec<String> = steps.iter().map(|step| format!("{:?}", step)).collect();
+
+            writeln!(f, "{:<4} x{:<6} {} ({} each)", get_opcode_name(opcode), count, names.join(" -> "), steps.len())?;
+        }
+
+        writeln!(
+            f,
+            "{} instructions, {} micro-steps (single-cycle: {} cycles)",
+            self.instructions.len(),
+            self.total_micro_steps,
+            self.instructions.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn a_load_takes_a_memory_step_and_an_add_does_not() {
+        let program = assemble("lod r0 40\nadd r0 r0 r1\n").unwrap();
+
+        assert_eq!(micro_steps(program[0]), [MicroStep::Fetch, MicroStep::Decode, MicroStep::Memory, MicroStep::Writeback]);
+        assert!(!micro_steps(program[1]).contains(&MicroStep::Memory));
+
+        let report = analyze(&program);
+
+        assert_eq!(report.total_micro_steps, 8);
+        assert!(report.to_string().ends_with("2 instructions, 8 micro-steps (single-cycle: 2 cycles)\n"));
+    }
+}
@@ -0,0 +1,100 @@
+//! A teaching model of a three-stage (fetch, decode, execute) pipeline without
+//! forwarding. It estimates stalls from register dependencies in an executed
+//! instruction stream and has no effect on the functional simulation.
+
+use crate::OPERAND_MASK;
+
+/// A decode that had to wait for an earlier instruction to write a register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stall {
+    /// Position of the stalled instruction in the stream.
+    pub index: usize,
+    /// The register it was waiting to read.
+    pub register: u32,
+    pub cycles: u64
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineReport {
+    pub instructions: usize,
+    pub stalls: Vec<Stall>,
+    /// Cycles from the first fetch to the last execute, including pipeline fill.
+    pub total_cycles: u64
+}
+
+/// Registers an instruction reads in its decode stage.
+pub(crate) fn reads(instruction: u32) -> Vec<u32> {
+    let operand = instruction & OPERAND_MASK;
+
+    match instruction >> 18 {
+        2 | 3 | 11..=14 | 29 | 33 => vec![operand >> 4, (operand & 0b001100) >> 2],
+        4 | 9 | 16 | 17 | 30 => vec![operand & 0b11],
+        // The destination too, since it keeps its value when the condition fails.
+        24..=26 => vec![(operand >> 2) & 0b11, operand & 0b11],
+        31 => vec![(operand >> 2) & 0b11],
+        32 => vec![(operand >> 2) & 0b11, operand & 0b11],
+        _ => Vec::new()
+    }
+}
+
+/// The register an instruction writes in its execute stage, if any.
+pub(crate) fn writes(instruction: u32) -> Option<u32> {
+    let operand = instruction & OPERAND_MASK;
+
+    match instruction >> 18 {
+        1..=3 | 10..=14 | 16 | 21 | 23..=26 | 29..=31 | 33 => Some(operand & 0b11),
+        _ => None
+    }
+}
+
+/// Runs `stream` (instruction words in the order they executed) through the pipeline
+/// model. A register written in execute can be read by a decode in the next cycle, so
+/// an instruction that reads the result of the one right before it stalls for a cycle.
+pub fn analyze(stream: &[u32]) -> PipelineReport {
+    // The earliest cycle each register's latest value can be read in decode.
+    let mut ready = [0u64; 4];
+    let mut stalls = Vec::new();
+    let mut decode_cycle = 0;
+
+    for (index, &instruction) in stream.iter().enumerate() {
+        decode_cycle += 1;
+
+        for register in reads(instruction) {
+            let available = ready[register as usize % ready.len()];
+
+            if available > decode_cycle {
+                stalls.push(Stall { index, register, cycles: available - decode_cycle });
+                decode_cycle = available;
+            }
+        }
+
+        if let Some(register) = writes(instruction) {
+            ready[register as usize % ready.len()] = decode_cycle + 2;
+        }
+    }
+
+    PipelineReport {
+        instructions: stream.len(),
+        stalls,
+        total_cycles: if stream.is_empty() { 0 } else { decode_cycle + 2 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn reading_the_previous_result_stalls_a_cycle() {
+        let report = analyze(&assemble("ldi 1 r1\nadd r1 r1 r2\nhlt\n").unwrap());
+
+        assert_eq!(report.stalls, [Stall { index: 1, register: 1, cycles: 1 }]);
+        assert_eq!(report.total_cycles, 6);
+
+        let report = analyze(&assemble("ldi 1 r1\nldi 2 r2\nhlt\n").unwrap());
+
+        assert!(report.stalls.is_empty());
+        assert_eq!(report.total_cycles, 5);
+    }
+}
@@ -0,0 +1,311 @@
+//! Deterministic re-runs. A `Recording` holds everything a run depends on besides
+//! the processor's configuration: the machine state it starts from, the random
+//! seed and the input queue. Restoring it onto a processor configured the same way
+//! runs the program again cycle for cycle. `diff_runs` goes the other way, running
+//! one program on two inputs to find where they part.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Processor, ProcessorState, RestoreError, Verbosity};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub state: ProcessorState,
+    pub random_seed: u32,
+    /// The values waiting on the input port, oldest first.
+    pub input: Vec<u32>
+}
+
+impl Recording {
+    /// Captures what `cpu` would run from if it were started now.
+    pub fn capture(cpu: &Processor) -> Recording {
+        Recording { state: cpu.state(), random_seed: cpu.random_seed(), input: cpu.pending_input().collect() }
+    }
+
+    /// Puts `cpu` back where the recording started, replacing its state, random
+    /// seed and input queue. In `MemoryModel::Harvard` the instruction ROM isn't
+    /// part of the state, so load the program before restoring. A state `cpu` can't
+    /// take is turned down as by `Processor::restore`, leaving `cpu` untouched.
+    pub fn restore(&self, cpu: &mut Processor) -> Result<(), RestoreError> {
+        cpu.restore(&self.state)?;
+        cpu.set_random_seed(self.random_seed);
+        cpu.clear_input();
+
+        for &value in &self.input {
+            cpu.push_input(value);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordingError {
+    MissingField(&'static str),
+    InvalidNumber { field: &'static str, text: String }
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordingError::MissingField(field) => write!(f, "recording has no '{}' line", field),
+            RecordingError::InvalidNumber { field, text } => write!(f, "'{}' in '{}' is not a number", text, field)
+        }
+    }
+}
+
+fn join(values: &[u32]) -> String {
+    values.iter().map(u32::to_string).collect::<Vec<String>>().join(" ")
+}
+
+/// One `field value...` line per item, words in decimal.
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "seed {}", self.random_seed)?;
+        writeln!(f, "pc {}", self.state.program_counter)?;
+        writeln!(f, "flags {}", self.state.flag_register)?;
+        writeln!(f, "halt {}", self.state.halt as u32)?;
+        writeln!(f, "registers {}", join(&self.state.registers))?;
+        writeln!(f, "ram {}", join(&self.state.ram))?;
+        writeln!(f, "input {}", join(&self.input))
+    }
+}
+
+impl FromStr for Recording {
+    type Err = RecordingError;
+
+    fn from_str(text: &str) -> Result<Recording, RecordingError> {
+        let values = |field: &'static str| -> Result<Vec<u32>, RecordingError> {
+            let line = text
+                .lines()
+                .find(|line| line.split_whitespace().next() == Some(field))
+                .ok_or(RecordingError::MissingField(field))?;
+
+            line.split_whitespace()
+                .skip(1)
+                .map(|term| term.parse().map_err(|_| RecordingError::InvalidNumber { field, text: term.to_string() }))
+                .collect()
+        };
+        let value = |field: &'static str| -> Result<u32, RecordingError> {
+            values(field)?.first().copied().ok_or(RecordingError::MissingField(field))
+        };
+
+        let random_seed = value("seed")?;
+        let program_counter = value("pc")? as usize;
+        let flag_register = value("flags")?;
+        let halt = value("halt")? != 0;
+        let registers = values("registers")?;
+        let ram = values("ram")?;
+
+        Ok(Recording {
+            state: ProcessorState { registers, program_counter, ram, flag_register, halt },
+            random_seed,
+            input: values("input")?
+        })
+    }
+}
+
+/// Where two runs of a program part, with the state of each run after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Instructions both runs executed before this one, counting from 0.
+    pub cycle: u64,
+    pub a: ProcessorState,
+    pub b: ProcessorState
+}
+
+/// Runs `program` twice in lockstep, once with `input_a` on the input port and once
+/// with `input_b`, and returns where the runs part: the first cycle after which
+/// their program counters differ, which is the branch that sent them down different
+/// paths. Runs that never part ways but still compute different values report the
+/// first cycle after which their registers or RAM differ instead, and runs that end
+/// the same return `None`. Each run stops as usual, at the latest at the default
+/// cycle limit; a run that stops while the other goes on parts from it as soon as
+/// the other moves on.
+pub fn diff_runs(program: &[u32], input_a: &[u32], input_b: &[u32]) -> Option<Divergence> {
+    let start = |input: &[u32]| {
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+        cpu.load_program(program);
+
+        for &value in input {
+            cpu.push_input(value);
+        }
+
+        cpu
+    };
+
+    let mut a = start(input_a);
+    let mut b = start(input_b);
+    let mut first_difference = None;
+
+    for cycle in 0.. {
+        let running_a = !a.step().is_stopped();
+        let running_b = !b.step().is_stopped();
+
+        let (state_a, state_b) = (a.state(), b.state());
+
+        if state_a.program_counter != state_b.program_counter {
+            return Some(Divergence { cycle, a: state_a, b: state_b });
+        }
+
+        if first_difference.is_none() && (state_a.registers != state_b.registers || state_a.ram != state_b.ram) {
+            first_difference = Some(Divergence { cycle, a: state_a, b: state_b });
+        }
+
+        if !running_a && !running_b {
+            break;
+        }
+    }
+
+    first_difference
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler, HaltReason, StepResult, Trap};
+
+    const SOURCE: &str = "
+.equ in 60
+.equ rand 61
+lod r0 in
+lod r1 rand
+add r0 r1 r2
+lod r3 in
+cmp 0 r3
+jeq done
+add r2 r3 r2
+done:
+hlt
+";
+
+    fn quiet() -> Processor {
+        let mut cpu = Processor::new();
+
+        cpu.set_verbosity(Verbosity::Quiet);
+
+        cpu
+    }
+
+    /// Steps `cpu` until it stops, returning why and its state after each step.
+    fn run_logged(cpu: &mut Processor) -> (HaltReason, Vec<ProcessorState>) {
+        let mut log = Vec::new();
+
+        loop {
+            let reason = cpu.step();
+
+            log.push(cpu.state());
+
+            if let StepResult::Stopped(reason) = reason {
+                return (reason, log);
+            }
+        }
+    }
+
+    #[test]
+    fn a_replay_matches_the_recorded_run() {
+        let mut cpu = quiet();
+
+        cpu.load_program(&assembler::assemble(SOURCE).unwrap());
+        cpu.set_random_seed(99);
+        cpu.push_input(5);
+        cpu.push_input(8);
+
+        let recording = Recording::capture(&cpu);
+        let (reason, log) = run_logged(&mut cpu);
+
+        let mut replayed = quiet();
+
+        recording.restore(&mut replayed).unwrap();
+
+        assert_eq!(reason, HaltReason::Halted);
+        assert_eq!(run_logged(&mut replayed), (reason, log));
+        assert_eq!(replayed.state(), cpu.state());
+    }
+
+    #[test]
+    fn a_recording_survives_its_text_form() {
+        let mut cpu = quiet();
+
+        cpu.load_program(&assembler::assemble(SOURCE).unwrap());
+        cpu.push_input(5);
+
+        let recording = Recording::capture(&cpu);
+
+        assert_eq!(recording.to_string().parse(), Ok(recording));
+        assert_eq!("seed 1\n".parse::<Recording>(), Err(RecordingError::MissingField("pc")));
+    }
+
+    #[test]
+    fn a_recording_the_machine_cant_take_is_turned_down() {
+        let mut cpu = quiet();
+        let mut recording = Recording::capture(&cpu);
+
+        recording.state.program_counter = 100;
+
+        let edited: Recording = recording.to_string().parse().unwrap();
+
+        assert_eq!(edited.restore(&mut cpu), Err(RestoreError::ProgramCounterOutOfRange(100)));
+        assert_eq!(cpu.program_counter(), 0);
+
+        recording.state.program_counter = 0;
+        recording.state.registers.push(0);
+
+        assert_eq!(
+            recording.restore(&mut cpu),
+            Err(RestoreError::SizeMismatch { ram_words: 64, registers: 5 })
+        );
+    }
+
+    #[test]
+    fn reading_an_empty_input_port_traps_until_input_arrives() {
+        let mut cpu = quiet();
+
+        cpu.load_program(&assembler::assemble(SOURCE).unwrap());
+
+        assert_eq!(cpu.run(), HaltReason::Trap(Trap::NoInput));
+        assert_eq!(cpu.program_counter(), 0);
+
+        cpu.push_input(5);
+        cpu.push_input(0);
+
+        assert_eq!(cpu.run(), HaltReason::Halted);
+        assert_eq!(cpu.registers()[0], 5);
+    }
+
+    #[test]
+    fn diff_runs_reports_the_branch_that_sends_the_runs_apart() {
+        // Both runs read 1 first. The second value differs, and the jeq on it is
+        // where they part.
+        let program = assembler::assemble("
+.equ in 60
+lod r0 in
+lod r1 in
+cmp 0 r1
+jeq zero
+ldi 5 r2
+zero:
+hlt
+").unwrap();
+
+        assert_eq!(diff_runs(&program, &[1, 0], &[1, 0]), None);
+
+        let divergence = diff_runs(&program, &[1, 0], &[1, 3]).unwrap();
+
+        assert_eq!(divergence.cycle, 3);
+        assert_eq!((divergence.a.program_counter, divergence.b.program_counter), (5, 4));
+        assert_eq!((divergence.a.registers[1], divergence.b.registers[1]), (0, 3));
+    }
+
+    #[test]
+    fn diff_runs_without_a_branch_reports_the_first_difference() {
+        let program = assembler::assemble(".equ in 60\nnop\nlod r0 in\nhlt\n").unwrap();
+        let divergence = diff_runs(&program, &[1], &[2]).unwrap();
+
+        assert_eq!(divergence.cycle, 1);
+        assert_eq!((divergence.a.registers[0], divergence.b.registers[0]), (1, 2));
+    }
+}